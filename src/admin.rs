@@ -0,0 +1,276 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::cache;
+use crate::config;
+use crate::cross;
+use crate::error;
+use crate::utils;
+
+// HTTP
+use axum::{
+    extract::{rejection::JsonRejection, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
+    Router,
+};
+
+// Data
+use serde::{Deserialize, Serialize};
+
+// Logs
+use tracing::{info, warn};
+
+// Standard
+use std::env;
+
+/*************
+ * Variables *
+ *************/
+
+// Scheme of the Authorization header
+const BEARER: &str = "Bearer ";
+
+/***********
+ * Structs *
+ ***********/
+
+#[derive(Serialize)]
+struct Sizes {
+    tiloud: Size,
+    cloud: Size,
+}
+
+#[derive(Serialize)]
+struct Size {
+    entries: u64,
+    // Weighted size, the same as the entries unless a weigher is set
+    size: u64,
+}
+
+// What to remove, everything when nothing is given
+#[derive(Deserialize)]
+struct Purge {
+    #[serde(default)]
+    cache: Target,
+    // West, south, east and north in degrees
+    bbox: Option<[f64; 4]>,
+    // Only tiles of this source
+    source: Option<String>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Target {
+    #[default]
+    All,
+    Tiloud,
+    Cloud,
+}
+
+#[derive(Serialize)]
+struct Purged {
+    tiloud: u64,
+    cloud: u64,
+    disk: u64,
+}
+
+#[derive(Serialize)]
+struct Reloaded {
+    sources: usize,
+    layers: usize,
+}
+
+/*************
+ * Functions *
+ *************/
+
+// Routes under /admin, all of them behind the token
+pub fn routes() -> Router<cache::Caches> {
+    Router::new()
+        .route("/unknown", get(unknown))
+        .route("/caches", get(caches))
+        .route("/purge", post(purge))
+        .route("/reload", post(reload))
+        .route_layer(middleware::from_fn(authorize))
+}
+
+// Refuse anyone without the configured bearer token
+async fn authorize(
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Result<Response, error::Failure> {
+    let config = config::get();
+    let expected = match env::var(&config.admin.token) {
+        Ok(token) if !token.is_empty() => token,
+        _ => {
+            return Err(error::Failure::new(
+                StatusCode::FORBIDDEN,
+                "admin_disabled",
+                format!("Set {} to enable administration", config.admin.token),
+            ))
+        }
+    };
+
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(BEARER));
+
+    match given {
+        Some(given) if same(given.as_bytes(), expected.as_bytes()) => Ok(next.run(request).await),
+        _ => {
+            let failure = error::Failure::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "A valid bearer token is required",
+            );
+            let mut response = failure.into_response();
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+            Ok(response)
+        }
+    }
+}
+
+// Comparison that takes as long wherever the first difference is
+fn same(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+async fn unknown() -> Json<Vec<cross::Unknown>> {
+    Json(cross::unknown_alerts())
+}
+
+async fn caches(State((cached, tiled)): State<cache::Caches>) -> Json<Sizes> {
+    // Counts lag behind until pending work is done
+    cached.run_pending_tasks().await;
+    tiled.run_pending_tasks().await;
+
+    Json(Sizes {
+        tiloud: Size {
+            entries: tiled.entry_count(),
+            size: tiled.weighted_size(),
+        },
+        cloud: Size {
+            entries: cached.entry_count(),
+            size: cached.weighted_size(),
+        },
+    })
+}
+
+async fn purge(
+    State((cached, tiled)): State<cache::Caches>,
+    purge: Result<Json<Purge>, JsonRejection>,
+) -> Result<Json<Purged>, error::Failure> {
+    let Json(purge) = purge
+        .map_err(|rejection| error::Failure::bad_request("invalid_body", rejection.body_text()))?;
+
+    // Area to match the cached tiles against
+    let area = match purge.bbox {
+        Some(bbox @ [west, south, east, north]) if west < east && south < north => {
+            Some(utils::bbox_plot(bbox))
+        }
+        Some(_) => {
+            return Err(error::Failure::bad_request(
+                "invalid_bbox",
+                "Bounding box must be west, south, east and north",
+            ))
+        }
+        None => None,
+    };
+    let inside = |pane: &utils::Plot| area.as_ref().is_none_or(|area| overlaps(pane, area));
+
+    let mut purged = Purged {
+        tiloud: 0,
+        cloud: 0,
+        disk: 0,
+    };
+
+    if purge.cache != Target::Cloud {
+        for (key, _) in tiled.iter() {
+            let (name, pane) = key.as_ref();
+            let chosen = purge.source.as_ref().is_none_or(|source| source == name);
+            if chosen && inside(&utils::grow_pad(0, pane)) {
+                tiled.invalidate(key.as_ref()).await;
+                purged.tiloud += 1;
+            }
+        }
+    }
+
+    // Otherwise they would be read back from disk
+    let config = config::get();
+    let directory = config
+        .cache
+        .directory
+        .as_ref()
+        .filter(|_| purge.cache != Target::Cloud);
+    if let Some(directory) = directory {
+        let chosen = |name: &str, pane: &utils::XYZ| {
+            purge.source.as_ref().is_none_or(|source| source == name)
+                && inside(&utils::grow_pad(0, pane))
+        };
+        purged.disk = cache::disk_purge(directory, chosen)
+            .await
+            .map_err(|error| {
+                error::Failure::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "purge_failed",
+                    format!("Tiles on disk could not be removed: {}", error),
+                )
+            })?;
+    }
+
+    // Alerts are fetched for a padded area around their key
+    if purge.cache != Target::Tiloud {
+        for (key, _) in cached.iter() {
+            if inside(&utils::grow_pad(utils::TILE_OFFSET, &key)) {
+                cached.invalidate(key.as_ref()).await;
+                purged.cloud += 1;
+            }
+        }
+    }
+
+    info!(
+        tiloud = purged.tiloud,
+        cloud = purged.cloud,
+        disk = purged.disk,
+        "Caches purged"
+    );
+
+    Ok(Json(purged))
+}
+
+async fn reload() -> Result<Json<Reloaded>, error::Failure> {
+    config::load().map_err(|message| {
+        warn!(%message, "Configuration not reloaded");
+        error::Failure::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid_config", message)
+    })?;
+
+    let config = config::get();
+    info!("Configuration reloaded");
+
+    Ok(Json(Reloaded {
+        sources: config.sources.len(),
+        layers: config.layers.len(),
+    }))
+}
+
+// Whether two areas share any part
+fn overlaps(one: &utils::Plot, other: &utils::Plot) -> bool {
+    one.top.lon <= other.bottom.lon
+        && other.top.lon <= one.bottom.lon
+        && one.bottom.lat <= other.top.lat
+        && other.bottom.lat <= one.top.lat
+}
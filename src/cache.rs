@@ -3,12 +3,22 @@
  ***********/
 
 // Time
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::time;
 
+// Files
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
 // Cache
 use moka::future::Cache;
 
+// Index
+use rstar::{RTree, RTreeObject, AABB};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
 // Traits
 use std::hash::Hash;
 
@@ -16,6 +26,66 @@ use std::hash::Hash;
 use crate::getter;
 use crate::utils;
 
+/*********
+ * Types *
+ *********/
+
+// Alerts indexed by their position
+pub type Alerts = RTree<Pinned>;
+
+// Alerts by the larger area they were fetched for
+pub type DataCache = Cache<utils::XYZ, Arc<Alerts>>;
+// Encoded tiles by source name and position
+pub type TileCache = Cache<(String, utils::XYZ), Vec<u8>>;
+// Shared by the handlers
+pub type Caches = (DataCache, TileCache);
+
+/***********
+ * Structs *
+ ***********/
+
+// Alert placed on the index by its longitude and latitude
+pub struct Pinned(pub getter::Alert);
+
+impl RTreeObject for Pinned {
+    type Envelope = AABB<[f64; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_point([self.0.position.lon, self.0.position.lat])
+    }
+}
+
+// Archives being read, by path and when they were written so replaced ones are opened again
+pub struct Opened<T>(Mutex<BTreeMap<(PathBuf, SystemTime), Arc<T>>>);
+
+impl<T> Opened<T> {
+    pub const fn new() -> Self {
+        Opened(Mutex::new(BTreeMap::new()))
+    }
+
+    // Archive already opened, or opened now when it is new or was replaced
+    pub fn get<F>(&self, path: &Path, open: F) -> Result<Arc<T>, String>
+    where
+        F: FnOnce(&Path) -> Result<T, String>,
+    {
+        let modified = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|error| error.to_string())?;
+
+        let mut opened = self.0.lock().unwrap();
+        opened.retain(|(known, written), _| known != path || *written == modified);
+        let key = (path.to_path_buf(), modified);
+        if let Some(archive) = opened.get(&key) {
+            return Ok(Arc::clone(archive));
+        }
+
+        let archive = Arc::new(open(path)?);
+        opened.insert(key, Arc::clone(&archive));
+
+        Ok(archive)
+    }
+}
+
 /*************
  * Variables *
  *************/
@@ -25,28 +95,153 @@ pub const CACHE_MAX: u64 = 0xFFFF;
 pub const CACHE_ZOOM: u16 = 10; // XYZ - Z
 pub const CACHE_TTL_TILE: u64 = 60; // Seconds
 pub const CACHE_TTL_DATA: u64 = 360; // Seconds
+pub const CACHE_TTL_DISK: u64 = 86400; // Seconds
+
+// Names given to the caches in metrics
+pub const CACHE_TILE: &str = "tiloud";
+pub const CACHE_DATA: &str = "cloud";
+pub const CACHE_DISK: &str = "disk";
+
+// Tells apart the partial files of a process
+static PARTIAL: AtomicU64 = AtomicU64::new(0);
 
 /*************
  * Functions *
  *************/
 
-// Find all alerts in an area
-pub fn find_alerts(map: &Vec<getter::Alert>, block: &utils::Plot) -> Vec<getter::Alert> {
-    // Initialise new vector
-    let mut found: Vec<getter::Alert> = Vec::new();
-
-    // Iterate the given map
-    for alert in map.iter() {
-        // Check bounds
-        if alert.position.lat < block.top.lat
-            && alert.position.lat > block.bottom.lat
-            && alert.position.lon < block.bottom.lon
-            && alert.position.lon > block.top.lon
-        {
-            found.push(alert.clone());
+// Location of a source tile on disk
+fn disk_path(directory: &Path, name: &str, pane: &utils::XYZ) -> PathBuf {
+    directory
+        .join(name)
+        .join(pane.z.to_string())
+        .join(pane.x.to_string())
+        .join(format!("{}.png", pane.y))
+}
+
+// Source tile on disk unless it is older than the expiry
+pub async fn disk_read(
+    directory: &Path,
+    expiry: u64,
+    name: &str,
+    pane: &utils::XYZ,
+) -> Option<Vec<u8>> {
+    let path = disk_path(directory, name, pane);
+    let modified = fs::metadata(&path).await.ok()?.modified().ok()?;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default();
+    if age > Duration::from_secs(expiry) {
+        return None;
+    }
+
+    fs::read(&path).await.ok()
+}
+
+// Keep a source tile on disk, replacing it at once so readers never see half of it
+pub async fn disk_write(
+    directory: &Path,
+    name: &str,
+    pane: &utils::XYZ,
+    bytes: &[u8],
+) -> std::io::Result<()> {
+    let path = disk_path(directory, name, pane);
+    let partial = path.with_extension(format!(
+        "{}.{}.partial",
+        std::process::id(),
+        PARTIAL.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(path.parent().unwrap()).await?;
+    fs::write(&partial, bytes).await?;
+    let renamed = fs::rename(&partial, &path).await;
+    if renamed.is_err() {
+        let _ = fs::remove_file(&partial).await;
+    }
+
+    renamed
+}
+
+// Remove the source tiles on disk that are chosen, giving how many were
+pub async fn disk_purge(
+    directory: &Path,
+    chosen: impl Fn(&str, &utils::XYZ) -> bool,
+) -> std::io::Result<u64> {
+    let mut removed = 0;
+
+    // Nothing was ever stored
+    let mut names = match fs::read_dir(directory).await {
+        Ok(names) => names,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(error) => return Err(error),
+    };
+
+    // Laid out as {name}/{z}/{x}/{y}.png, anything else is left alone
+    while let Some(name) = names.next_entry().await? {
+        let source = name.file_name().to_string_lossy().into_owned();
+        let mut zooms = fs::read_dir(name.path()).await?;
+        while let Some(zoom) = zooms.next_entry().await? {
+            let z = match numbered(&zoom.path(), "") {
+                Some(z) => z,
+                None => continue,
+            };
+            let mut columns = fs::read_dir(zoom.path()).await?;
+            while let Some(column) = columns.next_entry().await? {
+                let x = match numbered(&column.path(), "") {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let mut rows = fs::read_dir(column.path()).await?;
+                while let Some(row) = rows.next_entry().await? {
+                    let y = match numbered(&row.path(), "png") {
+                        Some(y) => y,
+                        None => continue,
+                    };
+                    let pane = utils::XYZ { x, y, z };
+                    if chosen(&source, &pane) {
+                        fs::remove_file(row.path()).await?;
+                        removed += 1;
+                    }
+                }
+            }
         }
     }
 
+    Ok(removed)
+}
+
+// Number a path of the disk cache is named after
+fn numbered<T: std::str::FromStr>(path: &Path, extension: &str) -> Option<T> {
+    if path
+        .extension()
+        .and_then(|given| given.to_str())
+        .unwrap_or("")
+        != extension
+    {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+// Build the index of a fetched area
+pub fn index_alerts(alerts: Vec<getter::Alert>) -> Alerts {
+    RTree::bulk_load(alerts.into_iter().map(Pinned).collect())
+}
+
+// Find all alerts in an area, edges included
+pub fn find_alerts(map: &Alerts, block: &utils::Plot) -> Vec<getter::Alert> {
+    let envelope = AABB::from_corners(
+        [block.top.lon, block.bottom.lat],
+        [block.bottom.lon, block.top.lat],
+    );
+
+    // Query the index
+    let mut found: Vec<getter::Alert> = map
+        .locate_in_envelope(envelope)
+        .map(|pinned| pinned.0.clone())
+        .collect();
+
+    // Keep the painting order
+    getter::alerts_sort(&mut found);
+
     found
 }
 
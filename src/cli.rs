@@ -0,0 +1,236 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::cache;
+use crate::config;
+use crate::logging;
+use crate::utils;
+
+// Arguments
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+
+// Standard
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/*************
+ * Variables *
+ *************/
+
+// Parsed once at start
+static ARGUMENTS: OnceLock<Cli> = OnceLock::new();
+
+/***********
+ * Structs *
+ ***********/
+
+/// Generates XYZ tiles while fetching and overlaying them from different places
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    /// Address to listen on
+    #[arg(long, env = "BIND", default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+    pub bind: IpAddr,
+
+    /// Port to listen on
+    #[arg(long, env = "PORT", default_value_t = 8080)]
+    pub port: u16,
+
+    /// Address clients reach the server at, such as https://tiles.example.com, taken from the request otherwise
+    #[arg(long, env = "PUBLIC_URL")]
+    pub public_url: Option<String>,
+
+    /// JSON configuration file, the defaults are used without it
+    #[arg(long, env = "CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Entries kept by each cache
+    #[arg(long, env = "CACHE_MAX", default_value_t = cache::CACHE_MAX)]
+    pub cache_max: u64,
+
+    /// Seconds between clearings of the tile cache
+    #[arg(
+        long,
+        env = "CACHE_TTL_TILE",
+        default_value_t = cache::CACHE_TTL_TILE,
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    pub cache_ttl_tile: u64,
+
+    /// Seconds between clearings of the alert cache
+    #[arg(
+        long,
+        env = "CACHE_TTL_DATA",
+        default_value_t = cache::CACHE_TTL_DATA,
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    pub cache_ttl_data: u64,
+
+    /// Shallowest zoom served by any layer
+    #[arg(
+        long,
+        env = "MIN_ZOOM",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u16).range(0..=i64::from(utils::MAX_ZOOM)),
+    )]
+    pub min_zoom: u16,
+
+    /// Deepest zoom served by any layer
+    #[arg(
+        long,
+        env = "MAX_ZOOM",
+        default_value_t = utils::MAX_ZOOM,
+        value_parser = clap::value_parser!(u16).range(0..=i64::from(utils::MAX_ZOOM)),
+    )]
+    pub max_zoom: u16,
+
+    /// Seconds given to requests in flight to finish once asked to stop
+    #[arg(long, env = "DRAIN_TIMEOUT", default_value_t = 30)]
+    pub drain_timeout: u64,
+
+    /// Filter of the logs, such as "debug" or "mapper=debug", RUST_LOG takes precedence
+    #[arg(long, env = "LOG_LEVEL", default_value = "info")]
+    pub log_level: String,
+
+    /// Format of the logs
+    #[arg(long, value_enum, env = "LOG_FORMAT", default_value = "text")]
+    pub log_format: logging::Format,
+
+    /// Serves tiles without one
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Fetch the tiles of an area ahead of time
+    Seed(Seed),
+    /// Render the tiles of an area into an MBTiles or PMTiles archive
+    Export(Export),
+}
+
+#[derive(Args)]
+pub struct Seed {
+    #[command(flatten)]
+    pub area: Area,
+
+    /// Write finished tiles, alerts included, to {output}/{z}/{x}/{y}.{format} instead of the disk cache
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct Export {
+    #[command(flatten)]
+    pub area: Area,
+
+    /// Archive to write, either .mbtiles or .pmtiles
+    #[arg(long)]
+    pub output: PathBuf,
+
+    /// Encoding of the tiles, the first of the layer by default
+    #[arg(long, value_enum)]
+    pub format: Option<config::Format>,
+
+    /// Name in the metadata, the layer by default
+    #[arg(long)]
+    pub name: Option<String>,
+
+    #[arg(long, default_value = "")]
+    pub description: String,
+
+    /// Attribution in the metadata, the layer's by default
+    #[arg(long)]
+    pub attribution: Option<String>,
+}
+
+// Tiles to walk through
+#[derive(Args)]
+pub struct Area {
+    /// Area as west,south,east,north in degrees
+    #[arg(long, value_parser = parse_bbox, allow_hyphen_values = true)]
+    pub bbox: [f64; 4],
+
+    /// Zoom or range of zooms, such as 8-14
+    #[arg(long, value_parser = parse_zooms)]
+    pub zooms: (u16, u16),
+
+    /// Layer to go through
+    #[arg(long, default_value = config::DEFAULT_LAYER)]
+    pub layer: String,
+
+    /// Tiles started per second
+    #[arg(long, default_value_t = 10.0)]
+    pub rate: f64,
+
+    /// Tiles fetched at the same time
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: usize,
+}
+
+/*************
+ * Functions *
+ *************/
+
+// Parse the command line and keep it around
+pub fn parse() -> &'static Cli {
+    ARGUMENTS.get_or_init(|| {
+        let cli = Cli::parse();
+        if cli.min_zoom > cli.max_zoom {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--min-zoom must not be above --max-zoom",
+                )
+                .exit();
+        }
+        cli
+    })
+}
+
+pub fn get() -> &'static Cli {
+    ARGUMENTS.get().expect("Arguments are parsed at start")
+}
+
+// West, south, east and north
+pub fn parse_bbox(given: &str) -> Result<[f64; 4], String> {
+    let parts = given
+        .split(',')
+        .map(|part| {
+            part.trim()
+                .parse::<f64>()
+                .map_err(|error| error.to_string())
+        })
+        .collect::<Result<Vec<f64>, String>>()?;
+
+    match parts[..] {
+        [west, south, east, north] if west < east && south < north => {
+            Ok([west, south, east, north])
+        }
+        [_, _, _, _] => Err("west must be below east and south below north".to_string()),
+        _ => Err("four numbers are needed".to_string()),
+    }
+}
+
+// Single zoom or an inclusive range
+fn parse_zooms(given: &str) -> Result<(u16, u16), String> {
+    let (from, to) = given.split_once('-').unwrap_or((given, given));
+    let from: u16 = from
+        .trim()
+        .parse()
+        .map_err(|_| format!("{} is not a zoom", from))?;
+    let to: u16 = to
+        .trim()
+        .parse()
+        .map_err(|_| format!("{} is not a zoom", to))?;
+
+    if from > to || to > utils::MAX_ZOOM {
+        return Err(format!("zooms go up from 0 to {}", utils::MAX_ZOOM));
+    }
+
+    Ok((from, to))
+}
@@ -0,0 +1,653 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::cache;
+use crate::utils;
+
+// Data
+use serde::Deserialize;
+
+// Arguments
+use clap::ValueEnum;
+
+// Standard
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, OnceLock, RwLock};
+
+/*************
+ * Variables *
+ *************/
+
+// Holder of the administration token unless configured otherwise
+const ADMIN_TOKEN_ENV: &str = "ADMIN_TOKEN";
+
+// Layer served when none is asked for
+pub const DEFAULT_LAYER: &str = "default";
+
+// XYZ Maps
+const LINKS: [(&str, &str); 1] = [(
+    "google",
+    "https://mts0.google.com/vt/lyrs=h,traffic&x={x}&y={y}&z={z}&style=3",
+)];
+
+// Sent upstream when nothing else is available
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+// Next entry of a rotating pool
+static ROTATION: AtomicUsize = AtomicUsize::new(0);
+
+// Location of the configuration file, if any
+static FILE: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+// Currently loaded configuration
+static CURRENT: LazyLock<RwLock<Arc<Config>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(Config::default())));
+
+/***********
+ * Structs *
+ ***********/
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub cache: Caching,
+    pub identity: Identity,
+    pub admin: Admin,
+    pub probe: Probe,
+    pub severity: Severity,
+    pub age: Age,
+    pub sources: BTreeMap<String, Source>,
+    pub layers: BTreeMap<String, Layer>,
+}
+
+// User Agent sent upstream
+#[derive(Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum Identity {
+    // The client's one, or the fallback when it has none
+    Passthrough {
+        #[serde(default = "user_agent")]
+        fallback: String,
+    },
+    // Always the same one
+    Fixed {
+        agent: String,
+    },
+    // A different one of the pool for every request
+    Rotating {
+        agents: Vec<String>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Admin {
+    // Environment variable holding the bearer token, administration is refused without it
+    pub token: String,
+}
+
+// Tile fetched from every source to tell whether upstreams are reachable
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Probe {
+    pub x: u32,
+    pub y: u32,
+    pub z: u16,
+    // Seconds a result is reused for
+    pub interval: u64,
+}
+
+// Upstream tiles, referenced by name from the layers
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Source {
+    Xyz(Xyz),
+    Wms(Wms),
+    Mbtiles(Archive),
+    Pmtiles(Archive),
+}
+
+#[derive(Deserialize)]
+pub struct Xyz {
+    // Template with {x}, {y}, {-y}, {z}, {q}, {s}, {r} and {key}
+    pub url: String,
+    // Rotated through for {s}
+    #[serde(default)]
+    pub subdomains: Vec<String>,
+    // Value of {r}, such as "@2x"
+    #[serde(default)]
+    pub retina: String,
+    // Environment variable holding the value of {key}
+    #[serde(default)]
+    pub key: Option<String>,
+    // Value of that variable, read once when loading
+    #[serde(skip)]
+    pub secret: Option<String>,
+    // Deepest zoom requested as four quadrants, past it tiles are requested as they are
+    #[serde(default = "default_quadrants")]
+    pub quadrants: u16,
+}
+
+// Web Map Service queried with GetMap in EPSG:3857
+#[derive(Deserialize)]
+pub struct Wms {
+    // Service endpoint, other parameters are added to it
+    pub url: String,
+    pub layers: Vec<String>,
+    #[serde(default)]
+    pub styles: Vec<String>,
+    #[serde(default = "wms_format")]
+    pub format: String,
+    #[serde(default = "wms_version")]
+    pub version: String,
+    #[serde(default = "default_true")]
+    pub transparent: bool,
+    // Pixels requested for each image
+    #[serde(default = "wms_size")]
+    pub size: u32,
+    // Deepest zoom requested as four quadrants, any area can be rendered so tiles are requested as they are by default
+    #[serde(default)]
+    pub quadrants: u16,
+}
+
+// Local file of pre-rendered tiles
+#[derive(Deserialize)]
+pub struct Archive {
+    pub path: PathBuf,
+    // Deepest zoom built out of four tiles of the next one, tiles are read as they are by default
+    #[serde(default)]
+    pub quadrants: u16,
+}
+
+// What gets served under a name
+#[derive(Deserialize)]
+pub struct Layer {
+    // From the bottom to the top
+    #[serde(default)]
+    pub sources: Vec<Composite>,
+    // Alerts are always painted on top of every source
+    #[serde(default = "default_true")]
+    pub alerts: bool,
+    #[serde(default)]
+    pub output: Output,
+    // What to answer with when there was nothing to paint
+    #[serde(default)]
+    pub empty: Empty,
+    // Served zoom range
+    #[serde(default)]
+    pub minzoom: u16,
+    #[serde(default = "default_maxzoom")]
+    pub maxzoom: u16,
+    // Extra levels past the maximum that are cut out of it and upscaled
+    #[serde(default)]
+    pub overzoom: u16,
+    // Shown by clients along with the tiles
+    #[serde(default)]
+    pub attribution: String,
+    // West, south, east and north in degrees where there is something to see
+    #[serde(default = "default_bounds")]
+    pub bounds: [f64; 4],
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Empty {
+    // Shared transparent tile
+    #[default]
+    Tile,
+    // Status 204 without a body
+    NoContent,
+}
+
+// Encoding of the finished tiles
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Output {
+    // By preference, the first one the client accepts is used
+    pub formats: Vec<Format>,
+    // From 1 to 100, used by the lossy formats
+    pub quality: u8,
+    // WebP without losses
+    pub lossless: bool,
+    // Colour under transparent parts for JPEG
+    pub background: [u8; 3],
+    pub png: Png,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Png {
+    // Indexed colours with transparency instead of full RGBA
+    pub quantize: bool,
+    // Size of the palette, up to 256
+    pub colours: u16,
+    // Deflate level from 0 to 9, balanced between size and speed when not given
+    pub compression: Option<u8>,
+    // Much quicker encoding for somewhat larger tiles, the level is then ignored
+    pub fast: bool,
+}
+
+#[derive(Deserialize, ValueEnum, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Png,
+    Webp,
+    Jpeg,
+    Avif,
+}
+
+// How a source is merged into the ones below it
+#[derive(Deserialize)]
+pub struct Composite {
+    pub source: String,
+    #[serde(default = "default_one")]
+    pub opacity: f32,
+    #[serde(default)]
+    pub blend: Blend,
+    // Amount of colour taken out, from 0 to 1
+    #[serde(default)]
+    pub grayscale: f32,
+    // Multiplier of every channel
+    #[serde(default = "default_one")]
+    pub brightness: f32,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Blend {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Caching {
+    // Alerts are fetched and cached for areas of this zoom
+    pub zoom: u16,
+    // Source tiles are also kept here, shared between instances and the seeder
+    pub directory: Option<PathBuf>,
+    // Seconds a tile on disk is used for
+    pub expiry: u64,
+}
+
+// Painting order of alerts, higher is drawn on top
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Severity {
+    // Keyed by either type or subtype, subtypes take precedence
+    pub ranks: HashMap<String, i32>,
+    // Rank used when neither the type or subtype are listed
+    pub fallback: i32,
+    // Outline drawn around the most important alerts
+    pub halo: Option<Halo>,
+}
+
+#[derive(Deserialize)]
+pub struct Halo {
+    // Minimum rank that gets the outline
+    pub from: i32,
+    pub colour: [u8; 4],
+    pub width: u32,
+}
+
+// Styling of alerts as they get older
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Age {
+    // The oldest step reached is the one used
+    pub steps: Vec<AgeStep>,
+}
+
+#[derive(Deserialize)]
+pub struct AgeStep {
+    // Minimum age in minutes
+    pub minutes: u64,
+    // Multiplier applied to the icon transparency
+    pub opacity: f32,
+    // Small mark on the corner of the icon
+    #[serde(default)]
+    pub badge: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cache: Caching::default(),
+            identity: Identity::default(),
+            admin: Admin::default(),
+            probe: Probe::default(),
+            severity: Severity::default(),
+            age: Age::default(),
+            sources: LINKS
+                .iter()
+                .map(|(name, url)| {
+                    let source = Source::Xyz(Xyz {
+                        url: url.to_string(),
+                        subdomains: Vec::new(),
+                        retina: String::new(),
+                        key: None,
+                        secret: None,
+                        quadrants: default_quadrants(),
+                    });
+                    (name.to_string(), source)
+                })
+                .collect(),
+            layers: BTreeMap::from([(
+                DEFAULT_LAYER.to_string(),
+                Layer {
+                    sources: LINKS
+                        .iter()
+                        .map(|(name, _)| Composite {
+                            source: name.to_string(),
+                            opacity: 1.0,
+                            blend: Blend::Normal,
+                            grayscale: 0.0,
+                            brightness: 1.0,
+                        })
+                        .collect(),
+                    alerts: true,
+                    output: Output::default(),
+                    empty: Empty::default(),
+                    minzoom: 0,
+                    maxzoom: default_maxzoom(),
+                    overzoom: 0,
+                    attribution: String::new(),
+                    bounds: default_bounds(),
+                },
+            )]),
+        }
+    }
+}
+
+impl Default for Caching {
+    fn default() -> Self {
+        Caching {
+            zoom: cache::CACHE_ZOOM,
+            directory: None,
+            expiry: cache::CACHE_TTL_DISK,
+        }
+    }
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Identity::Passthrough {
+            fallback: user_agent(),
+        }
+    }
+}
+
+impl Identity {
+    pub fn user_agent(&self, client: Option<&str>) -> String {
+        match self {
+            Identity::Passthrough { fallback } => client.unwrap_or(fallback).to_string(),
+            Identity::Fixed { agent } => agent.clone(),
+            Identity::Rotating { agents } => match agents.len() {
+                0 => user_agent(),
+                length => agents[ROTATION.fetch_add(1, Ordering::Relaxed) % length].clone(),
+            },
+        }
+    }
+}
+
+impl Default for Admin {
+    fn default() -> Self {
+        Admin {
+            token: ADMIN_TOKEN_ENV.to_string(),
+        }
+    }
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Probe {
+            x: 0,
+            y: 0,
+            z: 0,
+            interval: 30,
+        }
+    }
+}
+
+impl Source {
+    pub fn quadrants(&self) -> u16 {
+        match self {
+            Source::Xyz(xyz) => xyz.quadrants,
+            Source::Wms(wms) => wms.quadrants,
+            Source::Mbtiles(archive) | Source::Pmtiles(archive) => archive.quadrants,
+        }
+    }
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            formats: vec![Format::Png],
+            quality: 80,
+            lossless: false,
+            background: [0xFF, 0xFF, 0xFF],
+            png: Png::default(),
+        }
+    }
+}
+
+impl Default for Png {
+    fn default() -> Self {
+        Png {
+            quantize: false,
+            colours: 256,
+            compression: None,
+            fast: false,
+        }
+    }
+}
+
+impl Format {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Format::Png => "image/png",
+            Format::Webp => "image/webp",
+            Format::Jpeg => "image/jpeg",
+            Format::Avif => "image/avif",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Webp => "webp",
+            Format::Jpeg => "jpg",
+            Format::Avif => "avif",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Format> {
+        match extension {
+            "png" => Some(Format::Png),
+            "webp" => Some(Format::Webp),
+            "jpg" | "jpeg" => Some(Format::Jpeg),
+            "avif" => Some(Format::Avif),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Severity {
+    fn default() -> Self {
+        let ranks = [
+            ("JAM", 10),
+            ("JAM_STAND_STILL_TRAFFIC", 15),
+            ("MISC", 10),
+            ("POLICE", 20),
+            ("CONSTRUCTION", 20),
+            ("HAZARD", 30),
+            ("WEATHERHAZARD", 30),
+            ("ROAD_CLOSED", 40),
+            ("ACCIDENT", 50),
+            ("ACCIDENT_MAJOR", 60),
+        ];
+
+        Severity {
+            ranks: ranks
+                .iter()
+                .map(|(name, rank)| (name.to_string(), *rank))
+                .collect(),
+            fallback: 0,
+            halo: Some(Halo {
+                from: 50,
+                colour: [0xE4, 0x28, 0x28, 0xFF],
+                width: 3,
+            }),
+        }
+    }
+}
+
+impl Default for Age {
+    fn default() -> Self {
+        Age {
+            steps: vec![
+                AgeStep {
+                    minutes: 30,
+                    opacity: 0.85,
+                    badge: false,
+                },
+                AgeStep {
+                    minutes: 60,
+                    opacity: 0.65,
+                    badge: true,
+                },
+                AgeStep {
+                    minutes: 180,
+                    opacity: 0.45,
+                    badge: true,
+                },
+            ],
+        }
+    }
+}
+
+/*************
+ * Functions *
+ *************/
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_one() -> f32 {
+    1.0
+}
+
+fn default_quadrants() -> u16 {
+    14
+}
+
+fn default_maxzoom() -> u16 {
+    22
+}
+
+fn default_bounds() -> [f64; 4] {
+    [-180.0, -utils::MAX_LATITUDE, 180.0, utils::MAX_LATITUDE]
+}
+
+fn user_agent() -> String {
+    USER_AGENT.to_string()
+}
+
+fn wms_format() -> String {
+    "image/png".to_string()
+}
+
+fn wms_version() -> String {
+    "1.3.0".to_string()
+}
+
+fn wms_size() -> u32 {
+    utils::TILE_SIZE
+}
+
+// Remember where the configuration is read from
+pub fn locate(path: Option<PathBuf>) {
+    FILE.set(path).ok();
+}
+
+// Read the configuration from its file, the defaults without one
+pub fn read() -> Result<Config, String> {
+    let path = match FILE.get() {
+        Some(Some(path)) => path,
+        _ => return Ok(Config::default()),
+    };
+
+    let contents = fs::read_to_string(path)
+        .map_err(|error| format!("Config - {} - {}", path.display(), error))?;
+
+    let mut config: Config = serde_json::from_str(&contents)
+        .map_err(|error| format!("Config - {} - {}", path.display(), error))?;
+    read_keys(&mut config);
+    validate(&config).map_err(|error| format!("Config - {} - {}", path.display(), error))?;
+
+    Ok(config)
+}
+
+// Keys of the sources out of the environment
+fn read_keys(config: &mut Config) {
+    for source in config.sources.values_mut() {
+        if let Source::Xyz(xyz) = source {
+            xyz.secret = xyz.key.as_ref().and_then(|name| env::var(name).ok());
+        }
+    }
+}
+
+// Mistakes that would otherwise only show up as missing tiles
+fn validate(config: &Config) -> Result<(), String> {
+    for (name, source) in config.sources.iter() {
+        if let Source::Xyz(Xyz {
+            key: Some(key),
+            secret: None,
+            ..
+        }) = source
+        {
+            return Err(format!(
+                "source {} reads its key from {} which is not set",
+                name, key
+            ));
+        }
+    }
+
+    for (name, layer) in config.layers.iter() {
+        if let Some(composite) = layer
+            .sources
+            .iter()
+            .find(|composite| !config.sources.contains_key(&composite.source))
+        {
+            return Err(format!(
+                "layer {} uses source {} which does not exist",
+                name, composite.source
+            ));
+        }
+        if layer.minzoom > layer.maxzoom {
+            return Err(format!("layer {} has its minzoom above its maxzoom", name));
+        }
+    }
+
+    Ok(())
+}
+
+// Read and replace the current configuration
+pub fn load() -> Result<(), String> {
+    let config = read()?;
+    *CURRENT.write().unwrap() = Arc::new(config);
+    Ok(())
+}
+
+pub fn get() -> Arc<Config> {
+    CURRENT.read().unwrap().clone()
+}
@@ -2,7 +2,18 @@
  * Imports *
  ***********/
 
-use crate::print;
+// Mine
+use crate::config;
+
+// Data
+use serde::Serialize;
+
+// Logs
+use tracing::info;
+
+// Standard
+use std::collections::BTreeMap;
+use std::sync::{LazyLock, Mutex};
 
 /**********
  * Macros *
@@ -24,91 +35,238 @@ const DEFAULT: &str = "DEFAULT";
 const DEFAULT_INDEX: usize = 0;
 
 // Hazard
-const ALERT_TYPE_HAZARD: &[&str; 8] = &[
+const ALERT_TYPE_HAZARD: &[&str; 27] = &[
     DEFAULT,
-    "HAZARD_ON_ROAD_POT_HOLE",
+    "HAZARD_ON_ROAD",
+    "HAZARD_ON_ROAD_CAR_STOPPED",
     "HAZARD_ON_ROAD_CONSTRUCTION",
+    "HAZARD_ON_ROAD_EMERGENCY_VEHICLE",
     "HAZARD_ON_ROAD_ICE",
-    "HAZARD_ON_ROAD_TRAFFIC_LIGHT_FAULT",
+    "HAZARD_ON_ROAD_LANE_CLOSED",
     "HAZARD_ON_ROAD_OBJECT",
+    "HAZARD_ON_ROAD_OIL",
+    "HAZARD_ON_ROAD_POT_HOLE",
+    "HAZARD_ON_ROAD_ROAD_KILL",
+    "HAZARD_ON_ROAD_TRAFFIC_LIGHT_FAULT",
+    "HAZARD_ON_SHOULDER",
+    "HAZARD_ON_SHOULDER_ANIMALS",
     "HAZARD_ON_SHOULDER_CAR_STOPPED",
+    "HAZARD_ON_SHOULDER_MISSING_SIGN",
+    "HAZARD_WEATHER",
+    "HAZARD_WEATHER_FLOOD",
     "HAZARD_WEATHER_FOG",
+    "HAZARD_WEATHER_FREEZING_RAIN",
+    "HAZARD_WEATHER_HAIL",
+    "HAZARD_WEATHER_HEAT_WAVE",
+    "HAZARD_WEATHER_HEAVY_RAIN",
+    "HAZARD_WEATHER_HEAVY_SNOW",
+    "HAZARD_WEATHER_HURRICANE",
+    "HAZARD_WEATHER_MONSOON",
+    "HAZARD_WEATHER_TORNADO",
 ];
-const ALERT_TYPE_HAZARD_ASSETS: &[&[u8]; 8] = &[
+const ALERT_TYPE_HAZARD_ASSETS: &[&[u8]; 27] = &[
     bytes_asset!("hazard"),
-    bytes_asset!("pothole"),
+    bytes_asset!("hazard"),
+    bytes_asset!("vehicle-stopped"),
     bytes_asset!("construction"),
+    bytes_asset!("hazard"),
     bytes_asset!("ice"),
-    bytes_asset!("light"),
+    bytes_asset!("lane-closed"),
+    bytes_asset!("object"),
+    bytes_asset!("hazard"),
+    bytes_asset!("pothole"),
     bytes_asset!("object"),
+    bytes_asset!("light"),
+    bytes_asset!("hazard"),
+    bytes_asset!("hazard"),
     bytes_asset!("vehicle-stopped"),
+    bytes_asset!("hazard"),
+    bytes_asset!("rain"),
+    bytes_asset!("flood"),
     bytes_asset!("fog"),
+    bytes_asset!("ice"),
+    bytes_asset!("snow"),
+    bytes_asset!("hazard"),
+    bytes_asset!("rain"),
+    bytes_asset!("snow"),
+    bytes_asset!("rain"),
+    bytes_asset!("rain"),
+    bytes_asset!("rain"),
 ];
 
 // Jam
-const ALERT_TYPE_JAM: &[&str; 3] = &[DEFAULT, "JAM_HEAVY_TRAFFIC", "JAM_STAND_STILL_TRAFFIC"];
-const ALERT_TYPE_JAM_ASSETS: &[&[u8]; 3] = &[
+const ALERT_TYPE_JAM: &[&str; 5] = &[
+    DEFAULT,
+    "JAM_LIGHT_TRAFFIC",
+    "JAM_MODERATE_TRAFFIC",
+    "JAM_HEAVY_TRAFFIC",
+    "JAM_STAND_STILL_TRAFFIC",
+];
+const ALERT_TYPE_JAM_ASSETS: &[&[u8]; 5] = &[
+    bytes_asset!("traffic-low"),
+    bytes_asset!("traffic-low"),
     bytes_asset!("traffic-low"),
     bytes_asset!("traffic-low"),
     bytes_asset!("traffic-high"),
 ];
 
 // Closed
-const ALERT_ROAD_CLOSED: &[&str; 1] = &[DEFAULT];
-const ALERT_ROAD_CLOSED_ASSETS: &[&[u8]; 1] = &[bytes_asset!("closure")];
+const ALERT_ROAD_CLOSED: &[&str; 4] = &[
+    DEFAULT,
+    "ROAD_CLOSED_HAZARD",
+    "ROAD_CLOSED_CONSTRUCTION",
+    "ROAD_CLOSED_EVENT",
+];
+const ALERT_ROAD_CLOSED_ASSETS: &[&[u8]; 4] = &[
+    bytes_asset!("closure"),
+    bytes_asset!("closure"),
+    bytes_asset!("closure"),
+    bytes_asset!("closure"),
+];
 
 // Accident
-const ALERT_ACCIDENT: &[&str; 1] = &[DEFAULT];
-const ALERT_ACCIDENT_ASSETS: &[&[u8]; 1] = &[bytes_asset!("accident")];
+const ALERT_ACCIDENT: &[&str; 3] = &[DEFAULT, "ACCIDENT_MINOR", "ACCIDENT_MAJOR"];
+const ALERT_ACCIDENT_ASSETS: &[&[u8]; 3] = &[
+    bytes_asset!("accident"),
+    bytes_asset!("accident"),
+    bytes_asset!("accident-major"),
+];
 
 // Police
-const ALERT_POLICE: &[&str; 1] = &[DEFAULT];
-const ALERT_POLICE_ASSETS: &[&[u8]; 1] = &[bytes_asset!("police")];
+const ALERT_POLICE: &[&str; 4] = &[
+    DEFAULT,
+    "POLICE_VISIBLE",
+    "POLICE_HIDING",
+    "POLICE_WITH_MOBILE_CAMERA",
+];
+const ALERT_POLICE_ASSETS: &[&[u8]; 4] = &[
+    bytes_asset!("police"),
+    bytes_asset!("police"),
+    bytes_asset!("police-hiding"),
+    bytes_asset!("police"),
+];
+
+// Construction
+const ALERT_CONSTRUCTION: &[&str; 1] = &[DEFAULT];
+const ALERT_CONSTRUCTION_ASSETS: &[&[u8]; 1] = &[bytes_asset!("construction")];
+
+// Miscellaneous
+const ALERT_MISC: &[&str; 1] = &[DEFAULT];
+const ALERT_MISC_ASSETS: &[&[u8]; 1] = &[bytes_asset!("hazard")];
 
 // Correlation
-const ALERTS: [&str; 5] = ["HAZARD", "JAM", "ROAD_CLOSED", "ACCIDENT", "POLICE"];
-const SUB_ALERTS: [&[&str]; 5] = [
+const ALERTS: [&str; 8] = [
+    "HAZARD",
+    "WEATHERHAZARD",
+    "JAM",
+    "ROAD_CLOSED",
+    "ACCIDENT",
+    "POLICE",
+    "CONSTRUCTION",
+    "MISC",
+];
+const SUB_ALERTS: [&[&str]; 8] = [
+    ALERT_TYPE_HAZARD,
     ALERT_TYPE_HAZARD,
     ALERT_TYPE_JAM,
     ALERT_ROAD_CLOSED,
     ALERT_ACCIDENT,
     ALERT_POLICE,
+    ALERT_CONSTRUCTION,
+    ALERT_MISC,
 ];
-const SUB_ALERTS_ASSETS: [&[&[u8]]; 5] = [
+const SUB_ALERTS_ASSETS: [&[&[u8]]; 8] = [
+    ALERT_TYPE_HAZARD_ASSETS,
     ALERT_TYPE_HAZARD_ASSETS,
     ALERT_TYPE_JAM_ASSETS,
     ALERT_ROAD_CLOSED_ASSETS,
     ALERT_ACCIDENT_ASSETS,
     ALERT_POLICE_ASSETS,
+    ALERT_CONSTRUCTION_ASSETS,
+    ALERT_MISC_ASSETS,
 ];
 
+/***********
+ * Unknown *
+ ***********/
+
+// Every type and subtype pair that could not be matched, with its count
+static UNKNOWN: LazyLock<Mutex<BTreeMap<(String, String), u64>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+#[derive(Serialize)]
+pub struct Unknown {
+    #[serde(rename = "type")]
+    pub main_type: String,
+    pub sub_type: String,
+    pub count: u64,
+}
+
+// Count an unknown pair, only reporting it the first time it is seen
+fn report_unknown(main_type: &str, sub_type: &str) {
+    let mut unknown = UNKNOWN.lock().unwrap();
+    let count = unknown
+        .entry((main_type.to_string(), sub_type.to_string()))
+        .or_insert(0);
+
+    if *count == 0 {
+        info!(main_type, sub_type, "Alert icon not found");
+    }
+
+    *count += 1;
+}
+
+pub fn unknown_alerts() -> Vec<Unknown> {
+    UNKNOWN
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((main_type, sub_type), count)| Unknown {
+            main_type: main_type.clone(),
+            sub_type: sub_type.clone(),
+            count: *count,
+        })
+        .collect()
+}
+
 /**********
  * Finder *
  **********/
 
+// Note down alerts that have no mapping of their own
+pub fn track_alert(main_type: &str, sub_type: &str) {
+    let known = match ALERTS.iter().position(|&each| each == main_type) {
+        Some(item) => sub_type.is_empty() || SUB_ALERTS[item].contains(&sub_type),
+        None => false,
+    };
+
+    if !known {
+        report_unknown(main_type, sub_type);
+    }
+}
+
 pub fn find_alert_asset<'start, 'end>(main_type: &'start str, sub_type: &'start str) -> &'end [u8] {
     // Get Type
-    let found_main_type = match ALERTS.iter().position(|&each| each == main_type) {
-        Some(item) => item,
-        None => {
-            let information = format!("Alert - {} - Not Found", main_type);
-            print::print_info(&information);
-            DEFAULT_INDEX
-        }
-    };
+    let found_main_type = ALERTS
+        .iter()
+        .position(|&each| each == main_type)
+        .unwrap_or(DEFAULT_INDEX);
 
     // Otherwise we have a chance to narrow down
-    let found_sub_type = match SUB_ALERTS[found_main_type]
+    let found_sub_type = SUB_ALERTS[found_main_type]
         .iter()
         .position(|&each| each == sub_type)
-    {
-        Some(item) => item,
-        None => {
-            let information = format!("Sub Alert - {} > ({}) - Not Found", main_type, sub_type);
-            print::print_info(&information);
-            DEFAULT_INDEX
-        }
-    };
+        .unwrap_or(DEFAULT_INDEX);
 
     SUB_ALERTS_ASSETS[found_main_type][found_sub_type]
 }
+
+// Rank of an alert, subtypes are more specific so they win over types
+pub fn find_alert_severity(main_type: &str, sub_type: &str, severity: &config::Severity) -> i32 {
+    severity
+        .ranks
+        .get(sub_type)
+        .or_else(|| severity.ranks.get(main_type))
+        .copied()
+        .unwrap_or(severity.fallback)
+}
@@ -0,0 +1,70 @@
+/***********
+ * Imports *
+ ***********/
+
+// HTTP
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+
+// Data
+use serde::Serialize;
+
+// Mine
+use crate::NAME;
+
+/***********
+ * Structs *
+ ***********/
+
+// Error answered to the client as JSON
+pub struct Failure {
+    status: StatusCode,
+    error: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct Body<'a> {
+    error: &'a str,
+    message: &'a str,
+}
+
+/*************
+ * Functions *
+ *************/
+
+impl Failure {
+    pub fn new(status: StatusCode, error: &'static str, message: impl Into<String>) -> Self {
+        Failure {
+            status,
+            error,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(error: &'static str, message: impl Into<String>) -> Self {
+        Failure::new(StatusCode::BAD_REQUEST, error, message)
+    }
+
+    pub fn not_found(error: &'static str, message: impl Into<String>) -> Self {
+        Failure::new(StatusCode::NOT_FOUND, error, message)
+    }
+
+    // For facades that answer errors in their own way
+    pub fn into_parts(self) -> (StatusCode, &'static str, String) {
+        (self.status, self.error, self.message)
+    }
+}
+
+impl IntoResponse for Failure {
+    fn into_response(self) -> Response {
+        let body = Body {
+            error: self.error,
+            message: &self.message,
+        };
+
+        (self.status, [(header::SERVER, NAME)], Json(body)).into_response()
+    }
+}
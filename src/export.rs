@@ -0,0 +1,128 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::cli;
+use crate::config;
+use crate::mbtiles;
+use crate::pmtiles;
+use crate::seed;
+
+// Logs
+use tracing::{info, warn};
+
+// Standard
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/***********
+ * Structs *
+ ***********/
+
+// Description of an archive
+pub struct Metadata {
+    pub name: String,
+    pub description: String,
+    pub attribution: String,
+    pub format: config::Format,
+    // West, south, east and north in degrees
+    pub bounds: [f64; 4],
+    pub minzoom: u16,
+    pub maxzoom: u16,
+    // Either "baselayer" or "overlay"
+    pub kind: &'static str,
+}
+
+#[derive(Clone, Copy)]
+enum Archive {
+    Mbtiles,
+    Pmtiles,
+}
+
+/*************
+ * Functions *
+ *************/
+
+impl Metadata {
+    // Longitude, latitude and zoom to open the archive at
+    pub fn center(&self) -> (f64, f64, u16) {
+        let [west, south, east, north] = self.bounds;
+        ((west + east) / 2.0, (south + north) / 2.0, self.minzoom)
+    }
+}
+
+// Render every tile of an area into an archive
+pub async fn run(export: &cli::Export) -> Result<(), String> {
+    let archive = match export
+        .output
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some("mbtiles") => Archive::Mbtiles,
+        Some("pmtiles") => Archive::Pmtiles,
+        _ => return Err("Output must end with .mbtiles or .pmtiles".to_string()),
+    };
+
+    let config = config::get();
+    let tiles = Arc::new(Mutex::new(Vec::new()));
+    let target = seed::Target::Collect(Arc::clone(&tiles));
+    let job = seed::Job::new(&config, &export.area.layer, export.format, target)?;
+    let format = job.format;
+
+    let layer = &config.layers[&export.area.layer];
+    let zooms = seed::zooms(&export.area, layer, &job.target);
+    let (minzoom, maxzoom) = match (zooms.first(), zooms.last()) {
+        (Some(minzoom), Some(maxzoom)) => (*minzoom, *maxzoom),
+        _ => return Err("None of the zooms are served by the layer".to_string()),
+    };
+
+    // A partial snapshot is still worth having
+    let walked = seed::walk(&export.area, job).await;
+    if let Err(message) = &walked {
+        warn!(%message, "Exporting what was rendered");
+    }
+
+    let metadata = Metadata {
+        name: export.name.clone().unwrap_or(export.area.layer.clone()),
+        description: export.description.clone(),
+        attribution: export
+            .attribution
+            .clone()
+            .unwrap_or(layer.attribution.clone()),
+        format,
+        bounds: export.area.bbox,
+        minzoom,
+        maxzoom,
+        kind: if layer.sources.is_empty() {
+            "overlay"
+        } else {
+            "baselayer"
+        },
+    };
+    let tiles = mem::take(&mut *tiles.lock().unwrap());
+    let count = tiles.len();
+
+    // Archives are written in one go
+    let path = export.output.clone();
+    tokio::task::spawn_blocking(move || write(archive, &path, &metadata, tiles))
+        .await
+        .map_err(|error| error.to_string())??;
+
+    info!(tiles = count, path = %export.output.display(), "Exported");
+
+    walked
+}
+
+fn write(
+    archive: Archive,
+    path: &Path,
+    metadata: &Metadata,
+    tiles: seed::Rendered,
+) -> Result<(), String> {
+    match archive {
+        Archive::Mbtiles => mbtiles::write(path, metadata, &tiles),
+        Archive::Pmtiles => pmtiles::write(path, metadata, tiles),
+    }
+}
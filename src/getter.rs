@@ -3,19 +3,32 @@
  ***********/
 
 // Mine
-use crate::print;
+use crate::config;
+use crate::cross;
+use crate::mbtiles;
+use crate::pmtiles;
+use crate::telemetry;
 use crate::utils;
 
+// Logs
+use tracing::{debug, warn};
+
+// Links
+use url::Url;
+
+// Standard
+use std::time::Instant;
+
 /*************
  * Variables *
  *************/
 
-// XYZ Maps
-const LINKS: [&str; 1] = ["https://mts0.google.com/vt/lyrs=h,traffic&x={x}&y={y}&z={z}&style=3"];
-
 // WAZ
 const WAZ: &str = "https://embed.waze.com/live-map/api/georss?env=row&types=alerts&top={top}&bottom={bottom}&left={left}&right={right}";
 
+// Name of the alerts upstream in metrics and health
+pub const WAZ_SOURCE: &str = "waze";
+
 // Locators
 const IN_ALERTS: &str = "alerts";
 const IN_TYPE: &str = "type";
@@ -23,6 +36,7 @@ const IN_SUBTYPE: &str = "subtype";
 const IN_LOCATION: &str = "location";
 const IN_LOCATION_X: &str = "x";
 const IN_LOCATION_Y: &str = "y";
+const IN_PUBLISHED: &str = "pubMillis";
 
 /***********
  * Structs *
@@ -33,6 +47,8 @@ pub struct Alert {
     pub icon: String,
     pub subicon: String,
     pub position: utils::Coordinate,
+    // Milliseconds since epoch of when it was reported
+    pub published: Option<i64>,
 }
 
 /*************
@@ -47,95 +63,213 @@ fn replace_url_waz(input: &str, position: &utils::Plot) -> String {
         .replace("{right}", &position.bottom.lon.to_string())
 }
 
-fn replace_url(input: &str, position: &utils::XYZ) -> String {
-    input
+fn replace_url(source: &config::Xyz, position: &utils::XYZ) -> String {
+    // Same tile always goes to the same subdomain so it can be cached upstream
+    let subdomain = match source.subdomains.len() {
+        0 => "",
+        length => &source.subdomains[(position.x as usize + position.y as usize) % length],
+    };
+
+    source
+        .url
         .replace("{x}", &position.x.to_string())
         .replace("{y}", &position.y.to_string())
+        .replace("{-y}", &utils::tms_y(position).to_string())
         .replace("{z}", &position.z.to_string())
+        .replace("{q}", &utils::quadkey(position))
+        .replace("{s}", subdomain)
+        .replace("{r}", &source.retina)
+        .replace("{key}", source.secret.as_deref().unwrap_or_default())
+}
+
+fn wms_url(source: &config::Wms, position: &utils::XYZ) -> String {
+    // Bounding box in metres
+    let bounds = utils::xyz_to_mercator(position);
+    let bbox = format!(
+        "{},{},{},{}",
+        bounds.top.x, bounds.bottom.y, bounds.bottom.x, bounds.top.y
+    );
+
+    // Older versions call the reference system differently
+    let reference = if source.version == "1.3.0" {
+        "CRS"
+    } else {
+        "SRS"
+    };
+
+    let mut url = match Url::parse(&source.url) {
+        Ok(url) => url,
+        Err(_) => return source.url.clone(),
+    };
+    url.query_pairs_mut()
+        .append_pair("SERVICE", "WMS")
+        .append_pair("VERSION", &source.version)
+        .append_pair("REQUEST", "GetMap")
+        .append_pair("LAYERS", &source.layers.join(","))
+        .append_pair("STYLES", &source.styles.join(","))
+        .append_pair(reference, "EPSG:3857")
+        .append_pair("BBOX", &bbox)
+        .append_pair("WIDTH", &source.size.to_string())
+        .append_pair("HEIGHT", &source.size.to_string())
+        .append_pair("FORMAT", &source.format)
+        .append_pair(
+            "TRANSPARENT",
+            if source.transparent { "TRUE" } else { "FALSE" },
+        );
+
+    url.to_string()
+}
+
+fn source_url(source: &config::Source, position: &utils::XYZ) -> Option<String> {
+    match source {
+        config::Source::Xyz(xyz) => Some(replace_url(xyz, position)),
+        config::Source::Wms(wms) => Some(wms_url(wms, position)),
+        config::Source::Mbtiles(_) | config::Source::Pmtiles(_) => None,
+    }
 }
 
 pub async fn get_quadrant_tiles(
     user_agent: &str,
-    quadrants: &Vec<utils::XYZ>,
-) -> Vec<Vec<Vec<u8>>> {
+    name: &str,
+    source: &config::Source,
+    quadrants: &[utils::XYZ],
+) -> Option<Vec<Vec<u8>>> {
     // Initialise quadrants
-    let mut quadrants_tiles: Vec<Vec<Vec<u8>>> = Vec::new();
+    let mut quadrants_tiles: Vec<Vec<u8>> = Vec::new();
 
     let mut promises = Vec::new();
     for quadrant in quadrants.iter() {
-        let tiles = get_tiles(&user_agent, &quadrant);
-        promises.push(tiles);
+        let tile = get_source_tile(user_agent, name, source, quadrant);
+        promises.push(tile);
     }
 
-    // Fix structure
-    let tiles_length = LINKS.len();
-    let mut ordered_tiles: Vec<Vec<Vec<u8>>> = Vec::new();
-
+    // A missing quadrant would leave a hole in the tile
     for promise in promises {
-        quadrants_tiles.push(promise.await);
+        quadrants_tiles.push(promise.await?);
     }
 
-    for i in 0..tiles_length {
-        let mut temp: Vec<Vec<u8>> = Vec::new();
-        for j in 0..quadrants_tiles.len() {
-            temp.push(quadrants_tiles[j][i].clone());
+    Some(quadrants_tiles)
+}
+
+pub async fn get_source_tile(
+    user_agent: &str,
+    name: &str,
+    source: &config::Source,
+    position: &utils::XYZ,
+) -> Option<Vec<u8>> {
+    // URL, local archives are read instead
+    let url = match source_url(source, position) {
+        Some(url) => url,
+        None => return get_archive_tile(name, source, position).await,
+    };
+
+    // Tile
+    let started = Instant::now();
+    let tile = get_tile(&url, user_agent).await;
+
+    // Verbose
+    let elapsed = started.elapsed();
+    let elapsed_ms = elapsed.as_millis() as u64;
+    telemetry::upstream(name, outcome(&tile), elapsed);
+    match &tile {
+        Ok(_) => debug!(
+            x = position.x,
+            y = position.y,
+            z = position.z,
+            elapsed_ms,
+            "Tile fetched"
+        ),
+        Err(error) => {
+            warn!(x = position.x, y = position.y, z = position.z, elapsed_ms, %error, "Tile failed")
         }
-        ordered_tiles.push(temp);
     }
 
-    ordered_tiles
+    tile.ok()
 }
 
-pub async fn get_tiles(user_agent: &str, position: &utils::XYZ) -> Vec<Vec<u8>> {
-    // URLs
-    let mut urls: Vec<String> = Vec::new();
-    for url in LINKS.iter() {
-        urls.push(replace_url(&url, &position));
-    }
-
-    // Promises
-    let mut promises = Vec::new();
-    for url in urls.iter() {
-        promises.push(get_tile(&url, &user_agent));
-    }
+async fn get_archive_tile(
+    name: &str,
+    source: &config::Source,
+    position: &utils::XYZ,
+) -> Option<Vec<u8>> {
+    // Tile
+    let started = Instant::now();
+    let tile = match source {
+        config::Source::Mbtiles(archive) => mbtiles::read(&archive.path, position).await,
+        config::Source::Pmtiles(archive) => pmtiles::read(&archive.path, position).await,
+        config::Source::Xyz(_) | config::Source::Wms(_) => Ok(None),
+    };
 
     // Verbose
-    print::print_out_xyz(&position);
-
-    // Tiles
-    let mut tiles = Vec::new();
-    for promise in promises {
-        if let Ok(tile) = promise.await {
-            tiles.push(tile);
+    let elapsed = started.elapsed();
+    let elapsed_ms = elapsed.as_millis() as u64;
+    let status = match &tile {
+        Ok(Some(_)) => "2xx",
+        Ok(None) => "404",
+        Err(_) => "error",
+    };
+    telemetry::upstream(name, status.to_string(), elapsed);
+    match &tile {
+        Ok(Some(_)) => debug!(
+            x = position.x,
+            y = position.y,
+            z = position.z,
+            elapsed_ms,
+            "Tile read"
+        ),
+        Ok(None) => debug!(
+            x = position.x,
+            y = position.y,
+            z = position.z,
+            "Tile not in archive"
+        ),
+        Err(error) => {
+            warn!(x = position.x, y = position.y, z = position.z, elapsed_ms, %error, "Tile failed")
         }
     }
 
-    tiles
+    tile.ok().flatten()
 }
 
 pub async fn get_tile(url: &str, user_agent: &str) -> Result<Vec<u8>, reqwest::Error> {
     // Client
     let client = reqwest::Client::builder().user_agent(user_agent).build()?;
 
-    // Response
-    let response = client.get(url).send().await?;
-    let bytes = response.bytes().await?;
+    // Response, its URL can hold a key so it is kept out of errors
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(reqwest::Error::without_url)?;
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(reqwest::Error::without_url)?;
 
     Ok(bytes.to_vec())
 }
 
-pub async fn get_jsons(user_agent: &str, position: &utils::Plot) -> serde_json::Value {
+pub async fn get_jsons(user_agent: &str, position: &utils::Plot) -> Option<serde_json::Value> {
     // URLs
-    let url = replace_url_waz(&WAZ, &position);
+    let url = replace_url_waz(WAZ, position);
 
-    // Promise
-    let promise = get_json(&url, &user_agent);
+    // Data
+    let started = Instant::now();
+    let data = get_json(&url, user_agent).await;
 
     // Verbose
-    print::print_out_plot(&position);
+    let elapsed = started.elapsed();
+    let elapsed_ms = elapsed.as_millis() as u64;
+    telemetry::upstream(WAZ_SOURCE, outcome(&data), elapsed);
+    let (top, left) = (position.top.lat, position.top.lon);
+    let (bottom, right) = (position.bottom.lat, position.bottom.lon);
+    match &data {
+        Ok(_) => debug!(top, left, bottom, right, elapsed_ms, "Alerts fetched"),
+        Err(error) => warn!(top, left, bottom, right, elapsed_ms, %error, "Alerts failed"),
+    }
 
-    // Data
-    promise.await.unwrap_or(serde_json::json!({}))
+    data.ok()
 }
 
 pub async fn get_json(url: &str, user_agent: &str) -> Result<serde_json::Value, reqwest::Error> {
@@ -145,13 +279,32 @@ pub async fn get_json(url: &str, user_agent: &str) -> Result<serde_json::Value,
         .gzip(true)
         .build()?;
 
-    // Response
-    let response = client.get(url).send().await?;
-    let json = response.json::<serde_json::Value>().await?;
+    // Response, without its URL in errors like tiles
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(reqwest::Error::without_url)?;
+    let json = response
+        .json::<serde_json::Value>()
+        .await
+        .map_err(reqwest::Error::without_url)?;
 
     Ok(json)
 }
 
+// Status code of an answer, any success is the same and failures before getting one are an error
+fn outcome<T>(result: &Result<T, reqwest::Error>) -> String {
+    match result {
+        Ok(_) => "2xx".to_string(),
+        Err(error) => match error.status() {
+            Some(status) => status.as_u16().to_string(),
+            None => "error".to_string(),
+        },
+    }
+}
+
 pub fn alerts_extract(json: &serde_json::Value) -> Vec<Alert> {
     // Create local list of alerts
     let mut tidy: Vec<Alert> = Vec::new();
@@ -159,37 +312,108 @@ pub fn alerts_extract(json: &serde_json::Value) -> Vec<Alert> {
     // Iterate alerts
     if let Some(alerts) = json[IN_ALERTS].as_array() {
         for alert in alerts.iter() {
-            // Create alert
-            let item_alert = Alert {
-                icon: alert[IN_TYPE].as_str().unwrap().to_string(),
-                subicon: alert[IN_SUBTYPE].as_str().unwrap().to_string(),
-                position: utils::Coordinate {
-                    lat: alert[IN_LOCATION][IN_LOCATION_Y].as_f64().unwrap(),
-                    lon: alert[IN_LOCATION][IN_LOCATION_X].as_f64().unwrap(),
-                },
+            // Create alert, skipping the ones without a type or location
+            let item_alert = match alert_extract(alert) {
+                Some(item_alert) => item_alert,
+                None => continue,
             };
 
+            // Keep track of categories we can't draw
+            cross::track_alert(&item_alert.icon, &item_alert.subicon);
+
             // Add to the vector
             tidy.push(item_alert);
         }
     }
 
     // Sort vector
+    alerts_sort(&mut tidy);
+
+    tidy
+}
+
+fn alert_extract(alert: &serde_json::Value) -> Option<Alert> {
+    Some(Alert {
+        icon: alert[IN_TYPE].as_str()?.to_string(),
+        subicon: alert[IN_SUBTYPE].as_str().unwrap_or_default().to_string(),
+        position: utils::Coordinate {
+            lat: alert[IN_LOCATION][IN_LOCATION_Y].as_f64()?,
+            lon: alert[IN_LOCATION][IN_LOCATION_X].as_f64()?,
+        },
+        published: alert[IN_PUBLISHED].as_i64(),
+    })
+}
+
+// Northern alerts first so southern ones are painted over them
+pub fn alerts_sort(tidy: &mut [Alert]) {
     tidy.sort_by(|after, before| {
         if before.position.lat == after.position.lat {
-            before
-                .position
-                .lon
-                .partial_cmp(&after.position.lon)
-                .unwrap()
+            before.position.lon.total_cmp(&after.position.lon)
         } else {
-            before
-                .position
-                .lat
-                .partial_cmp(&after.position.lat)
-                .unwrap()
+            before.position.lat.total_cmp(&after.position.lat)
         }
     });
+}
 
-    tidy
+/*********
+ * Tests *
+ *********/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    // Log lines written while a test runs
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buffer);
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_fetches_keep_the_key_out_of_logs() {
+        let source: config::Source = serde_json::from_value(serde_json::json!({
+            "type": "xyz",
+            "url": "http://127.0.0.1:1/{z}/{x}/{y}.png?key={key}",
+            "key": "MAPPER_TEST_KEY",
+        }))
+        .unwrap();
+        let source = match source {
+            config::Source::Xyz(mut xyz) => {
+                xyz.secret = Some("hidden-secret".to_string());
+                config::Source::Xyz(xyz)
+            }
+            _ => unreachable!(),
+        };
+        let position = utils::XYZ { x: 1, y: 1, z: 20 };
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tile = tracing::subscriber::with_default(subscriber, || {
+            runtime.block_on(get_source_tile("test", "keyed", &source, &position))
+        });
+
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(tile.is_none());
+        assert!(logs.contains("Tile failed"));
+        assert!(!logs.contains("hidden-secret"));
+    }
 }
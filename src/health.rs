@@ -0,0 +1,126 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::config;
+use crate::getter;
+use crate::utils;
+
+// HTTP
+use axum::{http::StatusCode, response::Json};
+
+// Data
+use serde::Serialize;
+
+// Async
+use tokio::task;
+
+// Standard
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/*************
+ * Variables *
+ *************/
+
+// Last probe with the sources that failed it
+static LAST: Mutex<Option<(Instant, Vec<String>)>> = Mutex::new(None);
+
+/***********
+ * Structs *
+ ***********/
+
+#[derive(Serialize)]
+pub struct Health {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failing: Vec<String>,
+}
+
+/*************
+ * Functions *
+ *************/
+
+// The process is up and answering
+pub async fn healthz() -> Json<Health> {
+    Json(Health {
+        status: "alive",
+        failing: Vec::new(),
+    })
+}
+
+// Every upstream answers for the probe tile
+pub async fn readyz() -> (StatusCode, Json<Health>) {
+    let failing = probe().await;
+
+    if failing.is_empty() {
+        let health = Health {
+            status: "ready",
+            failing,
+        };
+        (StatusCode::OK, Json(health))
+    } else {
+        let health = Health {
+            status: "unready",
+            failing,
+        };
+        (StatusCode::SERVICE_UNAVAILABLE, Json(health))
+    }
+}
+
+// Upstreams failing the probe tile, reused for a while to spare the upstreams
+async fn probe() -> Vec<String> {
+    let config = config::get();
+    let interval = Duration::from_secs(config.probe.interval);
+
+    if let Some((checked, failing)) = LAST.lock().unwrap().as_ref() {
+        if checked.elapsed() < interval {
+            return failing.clone();
+        }
+    }
+
+    let position = utils::XYZ {
+        x: config.probe.x,
+        y: config.probe.y,
+        z: config.probe.z,
+    };
+
+    // Every source at once
+    let mut checks = Vec::new();
+    for name in config.sources.keys() {
+        let config = Arc::clone(&config);
+        let owned = name.clone();
+        let position = position.clone();
+        let check = task::spawn(async move {
+            let user_agent = config.identity.user_agent(None);
+            let source = &config.sources[&owned];
+            getter::get_source_tile(&user_agent, &owned, source, &position)
+                .await
+                .is_some()
+        });
+        checks.push((name.clone(), check));
+    }
+
+    // Alerts around the probe tile when a layer paints them
+    if config.layers.values().any(|layer| layer.alerts) {
+        let config = Arc::clone(&config);
+        let area = utils::grow_pad(0, &position);
+        let check = task::spawn(async move {
+            let user_agent = config.identity.user_agent(None);
+            getter::get_jsons(&user_agent, &area).await.is_some()
+        });
+        checks.push((getter::WAZ_SOURCE.to_string(), check));
+    }
+
+    let mut failing = Vec::new();
+    for (name, check) in checks {
+        if !check.await.unwrap_or(false) {
+            failing.push(name);
+        }
+    }
+
+    *LAST.lock().unwrap() = Some((Instant::now(), failing.clone()));
+
+    failing
+}
@@ -0,0 +1,50 @@
+/***********
+ * Imports *
+ ***********/
+
+// Logs
+use tracing_subscriber::EnvFilter;
+
+// Arguments
+use clap::ValueEnum;
+
+// Standard
+use std::io::{self, IsTerminal};
+
+/*************
+ * Variables *
+ *************/
+
+/***********
+ * Structs *
+ ***********/
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum Format {
+    // Human readable lines
+    Text,
+    // One object per line
+    Json,
+}
+
+/*************
+ * Functions *
+ *************/
+
+// Install the global subscriber, filtered with RUST_LOG or the given level
+pub fn init(format: Format, level: &str) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    // Colours only for people
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(io::stdout().is_terminal());
+
+    match format {
+        Format::Text => builder.init(),
+        Format::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .init(),
+    }
+}
@@ -3,55 +3,49 @@
  ***********/
 
 // Async
-use tokio::task;
+use tokio::{signal, sync::watch, task, time};
 // HTTP
-use axum::{
-    body::Body,
-    extract::{ConnectInfo, Query, State},
-    http::{header, StatusCode},
-    response::Response,
-    routing::get,
-    Router,
-};
-use axum_extra::{headers::UserAgent, TypedHeader};
-// Data
-use serde::Deserialize;
+use axum::{middleware, routing::get, Router};
 // Cache
 use moka::future::Cache;
+// Logs
+use tracing::{error, info, warn};
 // Standard
-use std::env;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::SocketAddr;
+use std::process;
+use std::time::Duration;
 
 // Utilities
+mod admin;
 mod cache;
+mod cli;
+mod config;
 mod cross;
+mod error;
+mod export;
 mod getter;
+mod health;
+mod logging;
+mod mbtiles;
 mod paint;
-mod print;
+mod pmtiles;
+mod render;
+mod seed;
+mod snapshot;
+mod telemetry;
+mod tilejson;
+mod tiles;
 mod utils;
+mod viewer;
+mod wmts;
 
 /*************
  * Constants *
  *************/
 
 // Address
-const ADDRESS: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
-const PORT_ENV: &str = "PORT";
-const PORT: u16 = 8080;
 const NAME: &str = "mapper";
 
-/**************
- * Structures *
- **************/
-
-// Query
-#[derive(Deserialize)]
-struct Arguments {
-    x: u32,
-    y: u32,
-    z: u16,
-}
-
 /********
  * Main *
  ********/
@@ -59,133 +53,122 @@ struct Arguments {
 // Main
 #[tokio::main]
 async fn main() {
+    // Arguments
+    let cli = cli::parse();
+
+    // Logs
+    logging::init(cli.log_format, &cli.log_level);
+
+    // Configuration
+    config::locate(cli.config.clone());
+    if let Err(message) = config::load() {
+        error!(%message, "Configuration not loaded");
+        process::exit(1);
+    }
+
+    // Metrics
+    telemetry::init();
+
+    // Other commands than serving
+    if let Some(command) = &cli.command {
+        let ran = match command {
+            cli::Command::Seed(seed) => seed::run(seed).await,
+            cli::Command::Export(export) => export::run(export).await,
+        };
+        if let Err(message) = ran {
+            error!(%message, "Command failed");
+            process::exit(1);
+        }
+        return;
+    }
+
     // Cache
-    let tiloud: Cache<utils::XYZ, Vec<Vec<u8>>> = Cache::new(cache::CACHE_MAX);
+    let tiloud: cache::TileCache = Cache::new(cli.cache_max);
     let clean_tiloud = tiloud.clone();
-    let cloud: Cache<utils::XYZ, Vec<getter::Alert>> = Cache::new(cache::CACHE_MAX);
+    let cloud: cache::DataCache = Cache::new(cli.cache_max);
     let clean_cloud = cloud.clone();
 
     // Clear cache periodically
-    let tile_cleaner = task::spawn(cache::clean_cache(
-        clean_tiloud.clone(),
-        cache::CACHE_TTL_TILE,
-    ));
-    let data_cleaner = task::spawn(cache::clean_cache(
-        clean_cloud.clone(),
-        cache::CACHE_TTL_DATA,
-    ));
+    let tile_cleaner = task::spawn(cache::clean_cache(clean_tiloud.clone(), cli.cache_ttl_tile));
+    let data_cleaner = task::spawn(cache::clean_cache(clean_cloud.clone(), cli.cache_ttl_data));
 
     // Build Web Application
     let app = Router::new()
         // Default Route
-        .route("/", get(default))
+        .route("/", get(tiles::default))
+        // Layers
+        .route("/{layer}/{z}/{x}/{tile}", get(tiles::layered))
+        // Descriptions of the layers
+        .route("/{file}", get(tilejson::tilejson))
+        // Images of any part of a layer
+        .route("/static", get(snapshot::snapshot))
+        // OGC Web Map Tile Service
+        .nest("/wmts", wmts::routes())
+        // Preview in a browser, with or without a trailing slash
+        .nest("/viewer", viewer::routes())
+        .route("/viewer/", get(viewer::page))
+        // Administration
+        .nest("/admin", admin::routes())
+        // Monitoring
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/metrics", get(telemetry::metrics))
+        .route_layer(middleware::from_fn(telemetry::track))
         .with_state((cloud.clone(), tiloud.clone()));
 
     // Create listener
-    let bind: String = format!(
-        "{}:{}",
-        ADDRESS,
-        env::var(PORT_ENV).unwrap_or(PORT.to_string())
-    );
-    let listener = tokio::net::TcpListener::bind(bind.as_str()).await.unwrap();
+    let bind = SocketAddr::new(cli.bind, cli.port);
+    let listener = tokio::net::TcpListener::bind(bind).await.unwrap();
 
     // Verbose
-    println!("Listening on {}", bind.as_str());
+    info!(%bind, "Listening");
 
-    // Server It
-    axum::serve(
+    // Stop taking connections when asked to, then let the ones in flight finish
+    let (stop, mut stopping) = watch::channel(false);
+    let server = axum::serve(
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
-    .await
-    .unwrap();
+    .with_graceful_shutdown(shutdown_signal(stop));
+    let drain = async {
+        stopping.wait_for(|stopped| *stopped).await.ok();
+        time::sleep(Duration::from_secs(cli.drain_timeout)).await;
+    };
 
-    tile_cleaner.await.unwrap();
-    data_cleaner.await.unwrap();
-}
+    // Server It
+    tokio::select! {
+        served = server => served.unwrap(),
+        _ = drain => warn!(seconds = cli.drain_timeout, "Requests cut off after draining"),
+    }
 
-/***********
- * Handler *
- ***********/
+    // Cleaners loop forever
+    tile_cleaner.abort();
+    data_cleaner.abort();
 
-// Basic
-async fn default(
-    State((cached, tiled)): State<(
-        Cache<utils::XYZ, Vec<getter::Alert>>,
-        Cache<utils::XYZ, Vec<Vec<u8>>>,
-    )>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    TypedHeader(raw_agent): TypedHeader<UserAgent>,
-    arguments: Query<Arguments>,
-) -> Response {
-    // User Agent
-    let user_agent = raw_agent.to_string();
-
-    // Convert inputs
-    let given_xyz = utils::XYZ {
-        x: arguments.x,
-        y: arguments.y,
-        z: arguments.z,
-    };
+    info!("Stopped");
+}
 
-    // Look for either the tile cache or get it
-    let cache_alerts = tiled.get(&given_xyz).await;
-    let data_chosen = async {
-        match cache_alerts {
-            Some(something) => something,
-            None => {
-                // Get 4 subtiles and merge them
-                let sub_tiles = utils::sub_tiles(&given_xyz);
-                let quadrants = getter::get_quadrant_tiles(&user_agent, &sub_tiles).await;
-                // Transform quadrants into tiles
-                // Also resize if needed
-                let fit = paint::join_quadrant_tiles(&quadrants).await;
-                // Cache them
-                tiled.insert(given_xyz.clone(), fit.clone()).await;
-                // Return
-                fit
-            }
-        }
+// Wait for SIGINT or SIGTERM
+async fn shutdown_signal(stop: watch::Sender<bool>) {
+    let interrupt = async {
+        signal::ctrl_c().await.unwrap();
     };
 
-    // Zoom out for a larger cached area and grow it
-    let cache_area = utils::zoom_scale(cache::CACHE_ZOOM, &given_xyz);
-    let cache_spaced = utils::grow_pad(utils::TILE_OFFSET, &cache_area);
-    // Generic big area that we will actually use for painting
-    let pings_spaced = utils::grow_pad(utils::TILE_OFFSET, &given_xyz);
-
-    // Look for cache and use it if present
-    let cache_alerts = cached.get(&cache_area).await;
-    let pings_chosen = async {
-        match cache_alerts {
-            Some(something) => something,
-            None => {
-                let data = getter::get_jsons(&user_agent, &cache_spaced);
-                let extracted = getter::alerts_extract(&data.await);
-                cached.insert(cache_area, extracted.clone()).await;
-                extracted
-            }
-        }
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
     };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    // Verbose
-    print::print_in(&addr.to_string(), &user_agent);
-
-    // Extract only the needed area
-    let pings_area = cache::find_alerts(&pings_chosen.await, &pings_spaced);
-
-    // Alerts to its own tile
-    let tiles_alerts = paint::alerts_to_tile(&pings_area, &pings_spaced);
-
-    // Join all tiles & extract its bytes
-    let tiles_joined = paint::join_tiles(&data_chosen.await, &tiles_alerts);
-    let tiles_bytes = paint::png_bytes(&tiles_joined);
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
 
-    // Response
-    Response::builder()
-        .status(StatusCode::OK)
-        .header(header::SERVER, NAME)
-        .header(header::CONTENT_TYPE, "image/png")
-        .body(Body::from(tiles_bytes))
-        .unwrap()
+    info!("Stopping, draining requests in flight");
+    stop.send(true).ok();
 }
@@ -0,0 +1,140 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::cache;
+use crate::config;
+use crate::export;
+use crate::utils;
+
+// Storage
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+// Async
+use tokio::task;
+
+// Standard
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+/*************
+ * Variables *
+ *************/
+
+// Specification followed
+const VERSION: &str = "1.3";
+
+const SCHEMA: &str = "
+    CREATE TABLE metadata (name TEXT, value TEXT);
+    CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+    CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);
+";
+
+// Archives being read
+static OPENED: cache::Opened<Mutex<Connection>> = cache::Opened::new();
+
+/*************
+ * Functions *
+ *************/
+
+// Formats outside of the specification go by their media type
+fn format_name(format: config::Format) -> &'static str {
+    match format {
+        config::Format::Png => "png",
+        config::Format::Jpeg => "jpg",
+        config::Format::Webp => "webp",
+        config::Format::Avif => format.mime(),
+    }
+}
+
+// Write a new archive, replacing any previous one
+pub fn write(
+    path: &Path,
+    metadata: &export::Metadata,
+    tiles: &[(utils::XYZ, Vec<u8>)],
+) -> Result<(), String> {
+    let failed = |error: rusqlite::Error| format!("MBTiles - {} - {}", path.display(), error);
+
+    if path.exists() {
+        fs::remove_file(path)
+            .map_err(|error| format!("MBTiles - {} - {}", path.display(), error))?;
+    }
+
+    let mut connection = Connection::open(path).map_err(failed)?;
+    connection.execute_batch(SCHEMA).map_err(failed)?;
+
+    let [west, south, east, north] = metadata.bounds;
+    let (lon, lat, zoom) = metadata.center();
+    let entries = [
+        ("name", metadata.name.clone()),
+        ("description", metadata.description.clone()),
+        ("attribution", metadata.attribution.clone()),
+        ("format", format_name(metadata.format).to_string()),
+        ("bounds", format!("{},{},{},{}", west, south, east, north)),
+        ("center", format!("{},{},{}", lon, lat, zoom)),
+        ("minzoom", metadata.minzoom.to_string()),
+        ("maxzoom", metadata.maxzoom.to_string()),
+        ("type", metadata.kind.to_string()),
+        ("version", VERSION.to_string()),
+    ];
+
+    // Everything at once
+    let transaction = connection.transaction().map_err(failed)?;
+    {
+        let mut insert = transaction
+            .prepare("INSERT INTO metadata (name, value) VALUES (?1, ?2)")
+            .map_err(failed)?;
+        for (name, value) in entries.iter() {
+            insert.execute(params![name, value]).map_err(failed)?;
+        }
+
+        // Rows are counted from the bottom
+        let mut insert = transaction
+            .prepare("INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)")
+            .map_err(failed)?;
+        for (position, bytes) in tiles.iter() {
+            insert
+                .execute(params![
+                    position.z,
+                    position.x,
+                    utils::tms_y(position),
+                    bytes
+                ])
+                .map_err(failed)?;
+        }
+    }
+    transaction.commit().map_err(failed)
+}
+
+fn open(path: &Path) -> Result<Mutex<Connection>, String> {
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map(Mutex::new)
+        .map_err(|error| error.to_string())
+}
+
+// Tile of an archive, nothing when it does not have it
+pub async fn read(path: &Path, position: &utils::XYZ) -> Result<Option<Vec<u8>>, String> {
+    let path = path.to_path_buf();
+    let position = position.clone();
+
+    task::spawn_blocking(move || {
+        let connection = OPENED
+            .get(&path, open)
+            .map_err(|error| format!("MBTiles - {} - {}", path.display(), error))?;
+        let connection = connection.lock().unwrap();
+
+        // Rows are counted from the bottom
+        connection
+            .query_row(
+                "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                params![position.z, position.x, utils::tms_y(&position)],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|error| format!("MBTiles - {} - {}", path.display(), error))
+    })
+    .await
+    .map_err(|error| error.to_string())?
+}
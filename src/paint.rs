@@ -3,20 +3,77 @@
  ***********/
 
 // Mine
+use crate::config;
 use crate::cross;
 use crate::getter;
 use crate::utils;
 
 // Image
-use image::{imageops, ImageFormat, RgbaImage};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{
+    imageops, ExtendedColorType, ImageEncoder, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage,
+};
+
+// Time
+use chrono::Utc;
+
+// Palette
+use color_quant::NeuQuant;
 
 // Bytes
+use std::cmp;
 use std::io::{BufWriter, Cursor};
+use std::sync::LazyLock;
 
 /*************
  * Variables *
  *************/
 
+// Faster AVIF encoding, from 1 to 10
+const AVIF_SPEED: u8 = 8;
+
+// Quality of the palette search, 1 is best and 30 is fastest
+const QUANTIZE_SAMPLING: i32 = 10;
+
+// Transparent tile encoded only once
+static EMPTY_PNG: LazyLock<Vec<u8>> = LazyLock::new(|| {
+    let mut buffer = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buffer, utils::TILE_SIZE, utils::TILE_SIZE);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::One);
+    encoder.set_palette(vec![0, 0, 0]);
+    encoder.set_trns(vec![0]);
+    encoder.set_deflate_compression(png::DeflateCompression::Level(9));
+    let mut writer = encoder.write_header().unwrap();
+    let row = utils::TILE_SIZE.div_ceil(8) as usize;
+    writer
+        .write_image_data(&vec![0; row * utils::TILE_SIZE as usize])
+        .unwrap();
+    writer.finish().unwrap();
+    buffer
+});
+static EMPTY_WEBP: LazyLock<Vec<u8>> = LazyLock::new(|| {
+    let mut buffer = Vec::new();
+    let blank = RgbaImage::new(utils::TILE_SIZE, utils::TILE_SIZE);
+    WebPEncoder::new_lossless(&mut buffer)
+        .write_image(
+            &blank,
+            blank.width(),
+            blank.height(),
+            ExtendedColorType::Rgba8,
+        )
+        .unwrap();
+    buffer
+});
+
+// Age badge
+const BADGE_RADIUS: u32 = 9;
+const BADGE_BORDER: u32 = 2;
+const BADGE_FILL: Rgba<u8> = Rgba([0x5F, 0x63, 0x68, 0xFF]);
+const BADGE_EDGE: Rgba<u8> = Rgba([0xFF, 0xFF, 0xFF, 0xFF]);
+
 /***********
  * Structs *
  ***********/
@@ -25,7 +82,12 @@ use std::io::{BufWriter, Cursor};
  * Functions *
  *************/
 
-pub fn alerts_to_tile(alerts: &Vec<getter::Alert>, spacer: &utils::Plot) -> RgbaImage {
+pub fn alerts_to_tile(alerts: &[getter::Alert], spacer: &utils::Plot) -> Option<RgbaImage> {
+    // Nothing to paint
+    if alerts.is_empty() {
+        return None;
+    }
+
     // Create our blank canvas
     let mut canvas = RgbaImage::new(utils::TILE_INFLATED, utils::TILE_INFLATED);
 
@@ -35,14 +97,54 @@ pub fn alerts_to_tile(alerts: &Vec<getter::Alert>, spacer: &utils::Plot) -> Rgba
         y: canvas.height(),
     };
 
+    paint_alerts(&mut canvas, alerts, |position| {
+        let confined = utils::coordinates_confine(position, spacer, &canvas_size);
+        utils::Pixel {
+            x: i64::from(confined.x),
+            y: i64::from(confined.y),
+        }
+    });
+
+    // Cropped to desired size
+    Some(RgbaImage::from(
+        imageops::crop(
+            &mut canvas,
+            utils::TILE_ORIGINAL_START,
+            utils::TILE_ORIGINAL_START,
+            utils::TILE_SIZE,
+            utils::TILE_SIZE,
+        )
+        .to_image(),
+    ))
+}
+
+// Paint alerts wherever their point falls on the canvas
+pub fn paint_alerts(
+    canvas: &mut RgbaImage,
+    alerts: &[getter::Alert],
+    locate: impl Fn(&utils::Coordinate) -> utils::Pixel,
+) {
+    // Least important first so the important ones end up on top
+    let config = config::get();
+    let severity = &config.severity;
+    let now = Utc::now().timestamp_millis();
+    let mut ordered: Vec<(i32, &getter::Alert)> = alerts
+        .iter()
+        .map(|alert| {
+            let rank = cross::find_alert_severity(&alert.icon, &alert.subicon, severity);
+            (rank, alert)
+        })
+        .collect();
+    ordered.sort_by_key(|(rank, _)| *rank);
+
     // Add the alerts to the canvas
-    for alert in alerts.iter() {
+    for (rank, alert) in ordered {
         // Translate the coordinates
-        let confined = utils::coordinates_confine(&alert.position, &spacer, &canvas_size);
+        let confined = locate(&alert.position);
 
         // Load icon
         let icon_bytes = cross::find_alert_asset(&alert.icon, &alert.subicon);
-        let icon_current = image::load_from_memory(icon_bytes).unwrap();
+        let mut icon_current = image::load_from_memory(icon_bytes).unwrap().to_rgba8();
         let icon_dimensions = utils::Raster {
             x: icon_current.width(),
             y: icon_current.height(),
@@ -51,54 +153,173 @@ pub fn alerts_to_tile(alerts: &Vec<getter::Alert>, spacer: &utils::Plot) -> Rgba
         // Fix edges
         let edges = utils::translate_edge(&icon_dimensions, &confined);
 
+        // Older alerts get styled differently
+        let step = alert
+            .published
+            .and_then(|published| age_step(&config.age, now - published));
+
+        // Highlight the most important ones
+        if let Some(halo) = &severity.halo {
+            if rank >= halo.from {
+                let mut around = outline(&icon_current, halo);
+                if let Some(step) = step {
+                    fade(&mut around, step.opacity);
+                }
+                let width = i64::from(halo.width);
+                imageops::overlay(canvas, &around, edges.x - width, edges.y - width);
+            }
+        }
+
+        // Age it
+        if let Some(step) = step {
+            fade(&mut icon_current, step.opacity);
+            if step.badge {
+                badge(&mut icon_current);
+            }
+        }
+
         // Overlay it
-        imageops::overlay(&mut canvas, &icon_current, edges.x as i64, edges.y as i64);
+        imageops::overlay(canvas, &icon_current, edges.x, edges.y);
     }
+}
 
-    // Cropped to desired size
-    RgbaImage::from(
-        imageops::crop(
-            &mut canvas,
-            utils::TILE_ORIGINAL_START,
-            utils::TILE_ORIGINAL_START,
+// Oldest step that was reached by the given age in milliseconds
+fn age_step(age: &config::Age, elapsed: i64) -> Option<&config::AgeStep> {
+    let minutes = u64::try_from(elapsed / 60_000).ok()?;
+    age.steps
+        .iter()
+        .filter(|step| step.minutes <= minutes)
+        .max_by_key(|step| step.minutes)
+}
+
+// Make an image more transparent
+fn fade(image: &mut RgbaImage, opacity: f32) {
+    let opacity = opacity.clamp(0.0, 1.0);
+    for pixel in image.pixels_mut() {
+        pixel[3] = (f32::from(pixel[3]) * opacity).round() as u8;
+    }
+}
+
+// Mark the top right corner of an icon
+fn badge(image: &mut RgbaImage) {
+    let outer = BADGE_RADIUS * BADGE_RADIUS;
+    let inner = (BADGE_RADIUS - BADGE_BORDER) * (BADGE_RADIUS - BADGE_BORDER);
+    let centre_x = image.width().saturating_sub(BADGE_RADIUS);
+    let centre_y = BADGE_RADIUS;
+
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let distance = centre_x.abs_diff(x).pow(2) + centre_y.abs_diff(y).pow(2);
+        if distance <= inner {
+            *pixel = BADGE_FILL;
+        } else if distance <= outer {
+            *pixel = BADGE_EDGE;
+        }
+    }
+}
+
+// Solid outline following the shape of an icon
+fn outline(icon: &RgbaImage, halo: &config::Halo) -> RgbaImage {
+    let width = i64::from(halo.width);
+    let reach = width * width;
+    let colour = Rgba(halo.colour);
+
+    // Leave space for the outline on every side
+    let mut around = RgbaImage::new(
+        icon.width() + (halo.width * 2),
+        icon.height() + (halo.width * 2),
+    );
+
+    // Grow every visible pixel by the width
+    for (x, y, pixel) in icon.enumerate_pixels() {
+        if pixel[3] == 0 {
+            continue;
+        }
+        for offset_y in -width..=width {
+            for offset_x in -width..=width {
+                if (offset_x * offset_x) + (offset_y * offset_y) > reach {
+                    continue;
+                }
+                let spot_x = (i64::from(x) + width + offset_x) as u32;
+                let spot_y = (i64::from(y) + width + offset_y) as u32;
+                around.put_pixel(spot_x, spot_y, colour);
+            }
+        }
+    }
+
+    around
+}
+
+pub async fn join_quadrant_tiles(tile: &[Vec<u8>]) -> Option<Vec<u8>> {
+    let image = if tile.len() == 1 {
+        image::load_from_memory(tile.first()?).ok()?.to_rgba8()
+    } else {
+        // New joined
+        let mut joiner = RgbaImage::new(utils::TILE_SIZE, utils::TILE_SIZE);
+        // Load images
+        let top_left = image::load_from_memory(tile.first()?).ok()?;
+        let top_right = image::load_from_memory(tile.get(1)?).ok()?;
+        let bottom_left = image::load_from_memory(tile.get(2)?).ok()?;
+        let bottom_right = image::load_from_memory(tile.get(3)?).ok()?;
+        // Size
+        let size = top_left.width().into();
+        // Overlay each
+        imageops::overlay(&mut joiner, &top_left, 0, 0);
+        imageops::overlay(&mut joiner, &top_right, size, 0);
+        imageops::overlay(&mut joiner, &bottom_left, 0, size);
+        imageops::overlay(&mut joiner, &bottom_right, size, size);
+
+        joiner
+    };
+
+    // Stretch the tile if needed
+    let insert_image = if image.width() != utils::TILE_SIZE {
+        imageops::resize(
+            &image,
             utils::TILE_SIZE,
             utils::TILE_SIZE,
+            imageops::FilterType::Nearest,
         )
-        .to_image(),
+    } else {
+        image
+    };
+
+    Some(png_bytes(&insert_image))
+}
+
+// Cut the part of a parent tile that covers a deeper one and stretch it
+pub fn overzoom(image: &RgbaImage, parent: &utils::XYZ, pane: &utils::XYZ) -> RgbaImage {
+    let levels = u32::from(pane.z - parent.z);
+    // Past a pixel per tile, the single pixel it falls in is stretched
+    let size = cmp::max(1, image.width() >> levels.min(31));
+
+    // Position of the deeper tile inside the parent, the pixel containing it when below one
+    let inside = |deeper: u32, shallower: u32, length: u32| {
+        let index = u64::from(deeper) - (u64::from(shallower) << levels);
+        ((index * u64::from(length)) >> levels) as u32
+    };
+    let offset_x = inside(pane.x, parent.x, image.width());
+    let offset_y = inside(pane.y, parent.y, image.height());
+
+    let cut = imageops::crop_imm(image, offset_x, offset_y, size, size).to_image();
+    imageops::resize(
+        &cut,
+        utils::TILE_SIZE,
+        utils::TILE_SIZE,
+        imageops::FilterType::Triangle,
     )
 }
 
-pub async fn join_quadrant_tiles(tiles: &Vec<Vec<Vec<u8>>>) -> Vec<Vec<u8>> {
+pub fn join_tiles(tiles: &[(&config::Composite, Vec<u8>)], tiled: Option<&RgbaImage>) -> RgbaImage {
     // Base
-    let mut all_tiles: Vec<Vec<u8>> = Vec::new();
+    let mut base = RgbaImage::new(utils::TILE_SIZE, utils::TILE_SIZE);
 
     // Add each tile
-    for tile in tiles.iter() {
-        let image = if tile.len() == 1 {
-            image::load_from_memory(&tile.first().unwrap())
-                .unwrap()
-                .to_rgba8()
-        } else {
-            // New joined
-            let mut joiner = RgbaImage::new(utils::TILE_SIZE, utils::TILE_SIZE);
-            // Load images
-            let top_left = image::load_from_memory(&tile[0]).unwrap();
-            let top_right = image::load_from_memory(&tile[1]).unwrap();
-            let bottom_left = image::load_from_memory(&tile[2]).unwrap();
-            let bottom_right = image::load_from_memory(&tile[3]).unwrap();
-            // Size
-            let size = top_left.width().into();
-            // Overlay each
-            imageops::overlay(&mut joiner, &top_left, 0, 0);
-            imageops::overlay(&mut joiner, &top_right, size, 0);
-            imageops::overlay(&mut joiner, &bottom_left, 0, size);
-            imageops::overlay(&mut joiner, &bottom_right, size, size);
-
-            joiner
+    for (composite, tile) in tiles.iter() {
+        let image = match image::load_from_memory(tile) {
+            Ok(image) => image.to_rgba8(),
+            Err(_) => continue,
         };
-
-        // Stretch the tile if needed
-        let insert_image = if image.width() != utils::TILE_SIZE {
+        let mut up = if image.width() != utils::TILE_SIZE {
             imageops::resize(
                 &image,
                 utils::TILE_SIZE,
@@ -108,38 +329,69 @@ pub async fn join_quadrant_tiles(tiles: &Vec<Vec<Vec<u8>>>) -> Vec<Vec<u8>> {
         } else {
             image
         };
+        filter(&mut up, composite);
+        blend(&mut base, &up, composite);
+    }
 
-        let png_data = png_bytes(&insert_image);
-        all_tiles.push(png_data);
+    // Add the last overlay tile
+    if let Some(tiled) = tiled {
+        imageops::overlay(&mut base, tiled, 0, 0);
     }
 
-    all_tiles
+    base
 }
 
-pub fn join_tiles(tiles: &Vec<Vec<u8>>, tiled: &RgbaImage) -> RgbaImage {
-    // Base
-    let mut base = RgbaImage::new(utils::TILE_SIZE, utils::TILE_SIZE);
+// Colour adjustments of a single source
+fn filter(image: &mut RgbaImage, composite: &config::Composite) {
+    let grayscale = composite.grayscale.clamp(0.0, 1.0);
+    let brightness = composite.brightness.max(0.0);
 
-    // Add each tile
-    for tile in tiles.iter() {
-        let image = image::load_from_memory(&tile).unwrap();
-        if image.width() != utils::TILE_SIZE {
-            let up = imageops::resize(
-                &image,
-                utils::TILE_SIZE,
-                utils::TILE_SIZE,
-                imageops::FilterType::Nearest,
-            );
-            imageops::overlay(&mut base, &up, 0, 0);
-        } else {
-            imageops::overlay(&mut base, &image, 0, 0);
+    // Nothing to change
+    if grayscale == 0.0 && brightness == 1.0 {
+        return;
+    }
+
+    for pixel in image.pixels_mut() {
+        let [red, green, blue, _] = pixel.0.map(f32::from);
+        let luma = (0.2126 * red) + (0.7152 * green) + (0.0722 * blue);
+        for channel in pixel.0.iter_mut().take(3) {
+            let value = f32::from(*channel);
+            let grey = value + ((luma - value) * grayscale);
+            *channel = (grey * brightness).round().clamp(0.0, 255.0) as u8;
         }
     }
+}
 
-    // Add the last overlay tile
-    imageops::overlay(&mut base, tiled, 0, 0);
+// Blend a source over the base, W3C compositing with source over
+fn blend(base: &mut RgbaImage, image: &RgbaImage, composite: &config::Composite) {
+    let opacity = composite.opacity.clamp(0.0, 1.0);
 
-    base
+    for (below, above) in base.pixels_mut().zip(image.pixels()) {
+        let alpha_above = (f32::from(above[3]) / 255.0) * opacity;
+        if alpha_above == 0.0 {
+            continue;
+        }
+        let alpha_below = f32::from(below[3]) / 255.0;
+        let alpha_out = alpha_above + (alpha_below * (1.0 - alpha_above));
+
+        for channel in 0..3 {
+            let colour_above = f32::from(above[channel]) / 255.0;
+            let colour_below = f32::from(below[channel]) / 255.0;
+            let mixed = match composite.blend {
+                config::Blend::Normal => colour_above,
+                config::Blend::Multiply => colour_below * colour_above,
+                config::Blend::Screen => {
+                    colour_below + colour_above - (colour_below * colour_above)
+                }
+            };
+            let source = ((1.0 - alpha_below) * colour_above) + (alpha_below * mixed);
+            let colour_out = ((alpha_above * source)
+                + (alpha_below * colour_below * (1.0 - alpha_above)))
+                / alpha_out;
+            below[channel] = (colour_out * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+        below[3] = (alpha_out * 255.0).round() as u8;
+    }
 }
 
 pub fn png_bytes(image: &RgbaImage) -> Vec<u8> {
@@ -152,3 +404,141 @@ pub fn png_bytes(image: &RgbaImage) -> Vec<u8> {
     // Get bytes
     buffer.into_inner().unwrap().into_inner()
 }
+
+pub fn encode(image: &RgbaImage, format: config::Format, output: &config::Output) -> Vec<u8> {
+    let quality = output.quality.clamp(1, 100);
+
+    // Buffer
+    let mut buffer = Vec::new();
+
+    match format {
+        config::Format::Png => return png_encode(image, &output.png),
+        config::Format::Webp if output.lossless => {
+            WebPEncoder::new_lossless(&mut buffer)
+                .write_image(
+                    image,
+                    image.width(),
+                    image.height(),
+                    ExtendedColorType::Rgba8,
+                )
+                .unwrap();
+        }
+        config::Format::Webp => {
+            let encoder = webp::Encoder::from_rgba(image, image.width(), image.height());
+            buffer = encoder.encode(f32::from(quality)).to_vec();
+        }
+        config::Format::Jpeg => {
+            let flat = flatten(image, output.background);
+            JpegEncoder::new_with_quality(&mut buffer, quality)
+                .write_image(&flat, flat.width(), flat.height(), ExtendedColorType::Rgb8)
+                .unwrap();
+        }
+        config::Format::Avif => {
+            AvifEncoder::new_with_speed_quality(&mut buffer, AVIF_SPEED, quality)
+                .write_image(
+                    image,
+                    image.width(),
+                    image.height(),
+                    ExtendedColorType::Rgba8,
+                )
+                .unwrap();
+        }
+    }
+
+    buffer
+}
+
+// Remove transparency by painting over a solid colour
+fn flatten(image: &RgbaImage, background: [u8; 3]) -> RgbImage {
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let pixel = image.get_pixel(x, y);
+        let alpha = u32::from(pixel[3]);
+        Rgb(std::array::from_fn(|channel| {
+            let above = u32::from(pixel[channel]) * alpha;
+            let below = u32::from(background[channel]) * (255 - alpha);
+            ((above + below + 127) / 255) as u8
+        }))
+    })
+}
+
+// Tile with nothing on it, shared when possible
+pub fn encode_empty(format: config::Format, output: &config::Output) -> Vec<u8> {
+    match format {
+        config::Format::Png => EMPTY_PNG.clone(),
+        config::Format::Webp => EMPTY_WEBP.clone(),
+        _ => encode(
+            &RgbaImage::new(utils::TILE_SIZE, utils::TILE_SIZE),
+            format,
+            output,
+        ),
+    }
+}
+
+pub fn png_encode(image: &RgbaImage, options: &config::Png) -> Vec<u8> {
+    // Nothing was painted
+    if image.width() == utils::TILE_SIZE
+        && image.height() == utils::TILE_SIZE
+        && image.pixels().all(|pixel| pixel[3] == 0)
+    {
+        return EMPTY_PNG.clone();
+    }
+
+    // Buffer
+    let mut buffer = Vec::new();
+
+    let mut encoder = png::Encoder::new(&mut buffer, image.width(), image.height());
+    encoder.set_depth(png::BitDepth::Eight);
+    match options.compression {
+        _ if options.fast => encoder.set_compression(png::Compression::Fast),
+        None => encoder.set_compression(png::Compression::Balanced),
+        Some(0) => encoder.set_deflate_compression(png::DeflateCompression::NoCompression),
+        Some(level) => {
+            encoder.set_deflate_compression(png::DeflateCompression::Level(level.min(9)))
+        }
+    }
+
+    // Either the palette and its indexes or the full pixels
+    let data = if options.quantize {
+        let (palette, indexes) = quantize(image, options.colours);
+        let (colours, alphas): (Vec<[u8; 3]>, Vec<u8>) = palette
+            .chunks_exact(4)
+            .map(|entry| ([entry[0], entry[1], entry[2]], entry[3]))
+            .unzip();
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_palette(colours.concat());
+        encoder.set_trns(alphas);
+        indexes
+    } else {
+        encoder.set_color(png::ColorType::Rgba);
+        image.as_raw().clone()
+    };
+
+    // Write image to buffer
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(&data).unwrap();
+    writer.finish().unwrap();
+
+    buffer
+}
+
+// Reduce to a palette, the last entry is kept fully transparent
+fn quantize(image: &RgbaImage, colours: u16) -> (Vec<u8>, Vec<u8>) {
+    let colours = usize::from(colours.clamp(2, 256)) - 1;
+    let quantizer = NeuQuant::new(QUANTIZE_SAMPLING, colours, image.as_raw());
+
+    let mut palette = quantizer.color_map_rgba();
+    palette.extend_from_slice(&[0, 0, 0, 0]);
+
+    let indexes = image
+        .pixels()
+        .map(|pixel| {
+            if pixel[3] == 0 {
+                colours as u8
+            } else {
+                quantizer.index_of(&pixel.0) as u8
+            }
+        })
+        .collect();
+
+    (palette, indexes)
+}
@@ -0,0 +1,577 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::cache;
+use crate::config;
+use crate::export;
+use crate::utils;
+
+// Compression
+use flate2::read::GzDecoder;
+
+// Async
+use tokio::task;
+
+// Standard
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::Mutex;
+
+/*************
+ * Variables *
+ *************/
+
+// Version 3 of the specification
+const MAGIC: &[u8; 7] = b"PMTiles";
+const VERSION: u8 = 3;
+const HEADER_LENGTH: usize = 127;
+
+// Header and root directory have to fit in the first read
+const ROOT_LIMIT: usize = 16384 - HEADER_LENGTH;
+
+// Entries per leaf directory to start with
+const LEAF_ENTRIES: usize = 4096;
+
+// Neither directories nor images are compressed when writing
+const COMPRESSION_NONE: u8 = 1;
+const COMPRESSION_GZIP: u8 = 2;
+
+// Root and at most three levels of leaves
+const DIRECTORY_DEPTH: usize = 4;
+
+// Archives being read
+static OPENED: cache::Opened<Reader> = cache::Opened::new();
+
+/***********
+ * Structs *
+ ***********/
+
+// Run of tiles with the same data, or a leaf directory when the run is 0
+#[derive(Clone, Copy)]
+pub struct Entry {
+    pub tile_id: u64,
+    pub offset: u64,
+    pub length: u32,
+    pub run_length: u32,
+}
+
+// Open archive with the parts of its header needed to find tiles
+pub struct Reader {
+    file: Mutex<File>,
+    root: Vec<Entry>,
+    leaves_offset: u64,
+    data_offset: u64,
+    internal_compression: u8,
+    tile_compression: u8,
+}
+
+/*************
+ * Functions *
+ *************/
+
+// Position along the Hilbert curves of every zoom up to this one
+pub fn tile_id(pane: &utils::XYZ) -> u64 {
+    let z = u32::from(pane.z);
+    let before = ((1_u64 << (2 * z)) - 1) / 3;
+    let n = 1_u64 << z;
+
+    let (mut x, mut y) = (u64::from(pane.x), u64::from(pane.y));
+    let mut along = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = u64::from(x & s > 0);
+        let ry = u64::from(y & s > 0);
+        along += s * s * ((3 * rx) ^ ry);
+
+        // Rotate the quadrant
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+
+    before + along
+}
+
+fn tile_type(format: config::Format) -> u8 {
+    match format {
+        config::Format::Png => 2,
+        config::Format::Jpeg => 3,
+        config::Format::Webp => 4,
+        config::Format::Avif => 5,
+    }
+}
+
+fn push_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+// Columns of identifier deltas, run lengths, lengths and offsets
+fn serialize(entries: &[Entry]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    push_varint(&mut buffer, entries.len() as u64);
+
+    let mut last = 0;
+    for entry in entries.iter() {
+        push_varint(&mut buffer, entry.tile_id - last);
+        last = entry.tile_id;
+    }
+    for entry in entries.iter() {
+        push_varint(&mut buffer, u64::from(entry.run_length));
+    }
+    for entry in entries.iter() {
+        push_varint(&mut buffer, u64::from(entry.length));
+    }
+
+    // Zero when right after the previous entry
+    for (index, entry) in entries.iter().enumerate() {
+        let follows = index > 0 && {
+            let previous = &entries[index - 1];
+            entry.offset == previous.offset + u64::from(previous.length)
+        };
+        push_varint(&mut buffer, if follows { 0 } else { entry.offset + 1 });
+    }
+
+    buffer
+}
+
+// Root directory and leaves, growing the leaves until the root fits
+fn directories(entries: &[Entry]) -> (Vec<u8>, Vec<u8>) {
+    let root = serialize(entries);
+    if root.len() <= ROOT_LIMIT {
+        return (root, Vec::new());
+    }
+
+    let mut leaf_entries = LEAF_ENTRIES;
+    loop {
+        let mut leaves = Vec::new();
+        let mut pointers = Vec::new();
+        for chunk in entries.chunks(leaf_entries) {
+            let leaf = serialize(chunk);
+            pointers.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u32,
+                run_length: 0,
+            });
+            leaves.extend(leaf);
+        }
+
+        let root = serialize(&pointers);
+        if root.len() <= ROOT_LIMIT {
+            return (root, leaves);
+        }
+        leaf_entries *= 2;
+    }
+}
+
+fn degrees(value: f64) -> [u8; 4] {
+    ((value * 10_000_000.0) as i32).to_le_bytes()
+}
+
+// Write a new archive, identical tiles are only stored once
+pub fn write(
+    path: &Path,
+    metadata: &export::Metadata,
+    tiles: Vec<(utils::XYZ, Vec<u8>)>,
+) -> Result<(), String> {
+    let failed = |error: std::io::Error| format!("PMTiles - {} - {}", path.display(), error);
+
+    // Ordered along the curve
+    let mut tiles: Vec<(u64, Vec<u8>)> = tiles
+        .into_iter()
+        .map(|(position, bytes)| (tile_id(&position), bytes))
+        .collect();
+    tiles.sort_by_key(|(id, _)| *id);
+    tiles.dedup_by_key(|(id, _)| *id);
+
+    // Place the data and group runs of the same tile
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut placed: HashMap<&[u8], (u64, u32)> = HashMap::new();
+    let mut contents: Vec<&[u8]> = Vec::new();
+    let mut data_length = 0_u64;
+    for (id, bytes) in tiles.iter() {
+        let (offset, length) = *placed.entry(bytes.as_slice()).or_insert_with(|| {
+            let place = (data_length, bytes.len() as u32);
+            data_length += bytes.len() as u64;
+            contents.push(bytes);
+            place
+        });
+
+        if let Some(last) = entries.last_mut() {
+            if last.offset == offset && last.tile_id + u64::from(last.run_length) == *id {
+                last.run_length += 1;
+                continue;
+            }
+        }
+        entries.push(Entry {
+            tile_id: *id,
+            offset,
+            length,
+            run_length: 1,
+        });
+    }
+
+    let (root, leaves) = directories(&entries);
+    let [west, south, east, north] = metadata.bounds;
+    let (lon, lat, zoom) = metadata.center();
+    let json = serde_json::json!({
+        "name": metadata.name,
+        "description": metadata.description,
+        "attribution": metadata.attribution,
+        "type": metadata.kind,
+        "format": metadata.format.extension(),
+    })
+    .to_string();
+
+    // Sections follow each other
+    let root_offset = HEADER_LENGTH as u64;
+    let metadata_offset = root_offset + root.len() as u64;
+    let leaves_offset = metadata_offset + json.len() as u64;
+    let data_offset = leaves_offset + leaves.len() as u64;
+
+    let mut header = Vec::with_capacity(HEADER_LENGTH);
+    header.extend(MAGIC);
+    header.push(VERSION);
+    for value in [
+        root_offset,
+        root.len() as u64,
+        metadata_offset,
+        json.len() as u64,
+        leaves_offset,
+        leaves.len() as u64,
+        data_offset,
+        data_length,
+        tiles.len() as u64,
+        entries.len() as u64,
+        contents.len() as u64,
+    ] {
+        header.extend(value.to_le_bytes());
+    }
+    header.extend([
+        1, // Clustered
+        COMPRESSION_NONE,
+        COMPRESSION_NONE,
+        tile_type(metadata.format),
+        metadata.minzoom as u8,
+        metadata.maxzoom as u8,
+    ]);
+    header.extend(degrees(west));
+    header.extend(degrees(south));
+    header.extend(degrees(east));
+    header.extend(degrees(north));
+    header.push(zoom as u8);
+    header.extend(degrees(lon));
+    header.extend(degrees(lat));
+
+    let mut file = BufWriter::new(File::create(path).map_err(failed)?);
+    file.write_all(&header).map_err(failed)?;
+    file.write_all(&root).map_err(failed)?;
+    file.write_all(json.as_bytes()).map_err(failed)?;
+    file.write_all(&leaves).map_err(failed)?;
+    for bytes in contents {
+        file.write_all(bytes).map_err(failed)?;
+    }
+    file.flush().map_err(failed)
+}
+
+fn read_varint(buffer: &[u8], position: &mut usize) -> Result<u64, String> {
+    let mut value = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *buffer
+            .get(*position)
+            .ok_or("Directory ends too early".to_string())?;
+        *position += 1;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte < 0x80 {
+            return Ok(value);
+        }
+    }
+
+    Err("Varint is too long".to_string())
+}
+
+fn deserialize(buffer: &[u8]) -> Result<Vec<Entry>, String> {
+    let mut position = 0;
+    let count = read_varint(buffer, &mut position)? as usize;
+    if count > buffer.len() {
+        return Err("Directory is too short for its entries".to_string());
+    }
+
+    let mut entries = Vec::with_capacity(count);
+    let mut last = 0;
+    for _ in 0..count {
+        last += read_varint(buffer, &mut position)?;
+        entries.push(Entry {
+            tile_id: last,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        });
+    }
+    for entry in entries.iter_mut() {
+        entry.run_length = read_varint(buffer, &mut position)? as u32;
+    }
+    for entry in entries.iter_mut() {
+        entry.length = read_varint(buffer, &mut position)? as u32;
+    }
+    for index in 0..count {
+        let offset = read_varint(buffer, &mut position)?;
+        entries[index].offset = match (offset, index) {
+            (0, 0) => return Err("First entry has no offset".to_string()),
+            (0, _) => entries[index - 1].offset + u64::from(entries[index - 1].length),
+            (offset, _) => offset - 1,
+        };
+    }
+
+    Ok(entries)
+}
+
+fn decompress(bytes: Vec<u8>, compression: u8) -> Result<Vec<u8>, String> {
+    match compression {
+        COMPRESSION_NONE => Ok(bytes),
+        COMPRESSION_GZIP => {
+            let mut inflated = Vec::new();
+            GzDecoder::new(bytes.as_slice())
+                .read_to_end(&mut inflated)
+                .map_err(|error| error.to_string())?;
+            Ok(inflated)
+        }
+        other => Err(format!("Compression {} is not supported", other)),
+    }
+}
+
+// Entry holding a tile, the last one starting before it
+fn find(entries: &[Entry], id: u64) -> Option<Entry> {
+    let index = entries.partition_point(|entry| entry.tile_id <= id);
+    let entry = *entries.get(index.checked_sub(1)?)?;
+
+    if entry.run_length == 0 || id < entry.tile_id + u64::from(entry.run_length) {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+impl Reader {
+    fn open(path: &Path) -> Result<Reader, String> {
+        let mut file = File::open(path).map_err(|error| error.to_string())?;
+        let mut header = [0_u8; HEADER_LENGTH];
+        file.read_exact(&mut header)
+            .map_err(|error| error.to_string())?;
+
+        if &header[0..7] != MAGIC || header[7] != VERSION {
+            return Err("Not a version 3 archive".to_string());
+        }
+        let number = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+
+        let mut reader = Reader {
+            file: Mutex::new(file),
+            root: Vec::new(),
+            leaves_offset: number(40),
+            data_offset: number(56),
+            internal_compression: header[97],
+            tile_compression: header[98],
+        };
+        let root = reader.range(number(8), number(16))?;
+        reader.root = deserialize(&decompress(root, reader.internal_compression)?)?;
+
+        Ok(reader)
+    }
+
+    fn range(&self, offset: u64, length: u64) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![0_u8; length as usize];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut bytes))
+            .map_err(|error| error.to_string())?;
+
+        Ok(bytes)
+    }
+
+    // Walk down the directories to the tile
+    fn tile(&self, position: &utils::XYZ) -> Result<Option<Vec<u8>>, String> {
+        let id = tile_id(position);
+        let mut leaf;
+        let mut directory = &self.root;
+
+        for _ in 0..DIRECTORY_DEPTH {
+            let entry = match find(directory, id) {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+
+            if entry.run_length > 0 {
+                let offset = self.data_offset + entry.offset;
+                let bytes = self.range(offset, u64::from(entry.length))?;
+                return decompress(bytes, self.tile_compression).map(Some);
+            }
+
+            let offset = self.leaves_offset + entry.offset;
+            let bytes = self.range(offset, u64::from(entry.length))?;
+            leaf = deserialize(&decompress(bytes, self.internal_compression)?)?;
+            directory = &leaf;
+        }
+
+        Err("Directories are too deep".to_string())
+    }
+}
+
+// Tile of an archive, nothing when it does not have it
+pub async fn read(path: &Path, position: &utils::XYZ) -> Result<Option<Vec<u8>>, String> {
+    let owned = path.to_path_buf();
+    let position = position.clone();
+
+    task::spawn_blocking(move || {
+        OPENED
+            .get(&owned, Reader::open)
+            .and_then(|reader| reader.tile(&position))
+    })
+    .await
+    .map_err(|error| error.to_string())?
+    .map_err(|error| format!("PMTiles - {} - {}", path.display(), error))
+}
+
+/*********
+ * Tests *
+ *********/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn xyz(z: u16, x: u32, y: u32) -> utils::XYZ {
+        utils::XYZ { x, y, z }
+    }
+
+    fn metadata(maxzoom: u16) -> export::Metadata {
+        export::Metadata {
+            name: "test".to_string(),
+            description: String::new(),
+            attribution: String::new(),
+            format: config::Format::Png,
+            bounds: [-180.0, -85.0, 180.0, 85.0],
+            minzoom: 0,
+            maxzoom,
+            kind: "overlay",
+        }
+    }
+
+    // Archive in the temporary directory, removed when dropped
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let file = format!("mapper-{}-{}.pmtiles", std::process::id(), name);
+            Scratch(std::env::temp_dir().join(file))
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn tile_id_follows_the_specification() {
+        assert_eq!(tile_id(&xyz(0, 0, 0)), 0);
+        assert_eq!(tile_id(&xyz(1, 0, 0)), 1);
+        assert_eq!(tile_id(&xyz(1, 0, 1)), 2);
+        assert_eq!(tile_id(&xyz(1, 1, 1)), 3);
+        assert_eq!(tile_id(&xyz(1, 1, 0)), 4);
+        assert_eq!(tile_id(&xyz(2, 0, 0)), 5);
+        assert_eq!(tile_id(&xyz(3, 0, 0)), 21);
+        assert_eq!(tile_id(&xyz(12, 3423, 1763)), 19078479);
+    }
+
+    #[test]
+    fn directory_round_trip() {
+        let entries = vec![
+            Entry {
+                tile_id: 0,
+                offset: 0,
+                length: 10,
+                run_length: 1,
+            },
+            Entry {
+                tile_id: 1,
+                offset: 10,
+                length: 300,
+                run_length: 4,
+            },
+            Entry {
+                tile_id: 70_000,
+                offset: 0,
+                length: 10,
+                run_length: 1,
+            },
+        ];
+
+        let parsed = deserialize(&serialize(&entries)).unwrap();
+        assert_eq!(parsed.len(), entries.len());
+        for (parsed, entry) in parsed.iter().zip(&entries) {
+            assert_eq!(parsed.tile_id, entry.tile_id);
+            assert_eq!(parsed.offset, entry.offset);
+            assert_eq!(parsed.length, entry.length);
+            assert_eq!(parsed.run_length, entry.run_length);
+        }
+    }
+
+    #[test]
+    fn written_tiles_read_back() {
+        let scratch = Scratch::new("root");
+        let tiles = vec![
+            (xyz(0, 0, 0), b"world".to_vec()),
+            (xyz(1, 0, 0), b"same".to_vec()),
+            (xyz(1, 0, 1), b"same".to_vec()),
+            (xyz(1, 1, 1), b"other".to_vec()),
+        ];
+        write(&scratch.0, &metadata(1), tiles.clone()).unwrap();
+
+        let reader = Reader::open(&scratch.0).unwrap();
+        for (position, bytes) in tiles {
+            assert_eq!(reader.tile(&position).unwrap(), Some(bytes));
+        }
+        assert_eq!(reader.tile(&xyz(1, 1, 0)).unwrap(), None);
+        assert_eq!(reader.tile(&xyz(2, 0, 0)).unwrap(), None);
+    }
+
+    #[test]
+    fn written_tiles_read_back_through_leaves() {
+        let scratch = Scratch::new("leaves");
+        let size = 1_u32 << 7;
+        let tiles: Vec<(utils::XYZ, Vec<u8>)> = (0..size)
+            .flat_map(|x| {
+                (0..size).map(move |y| (xyz(7, x, y), (x * size + y).to_le_bytes().to_vec()))
+            })
+            .collect();
+        write(&scratch.0, &metadata(7), tiles.clone()).unwrap();
+
+        // Too many tiles for the root directory alone
+        let header = fs::read(&scratch.0).unwrap();
+        let leaves_length = u64::from_le_bytes(header[48..56].try_into().unwrap());
+        assert!(leaves_length > 0);
+
+        let reader = Reader::open(&scratch.0).unwrap();
+        for (position, bytes) in tiles.into_iter().step_by(61) {
+            assert_eq!(reader.tile(&position).unwrap(), Some(bytes));
+        }
+        assert_eq!(reader.tile(&xyz(6, 0, 0)).unwrap(), None);
+    }
+}
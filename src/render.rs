@@ -0,0 +1,330 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::cache;
+use crate::cli;
+use crate::config;
+use crate::getter;
+use crate::paint;
+use crate::telemetry;
+use crate::utils;
+
+// Image
+use image::{imageops, RgbaImage};
+
+// Async
+use tokio::task::JoinSet;
+
+// Logs
+use tracing::{debug, warn};
+
+// Standard
+use std::cmp;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Instant;
+
+/*************
+ * Functions *
+ *************/
+
+// Zooms a layer is served at, within the ones the instance allows
+pub fn served_zooms(layer: &config::Layer) -> RangeInclusive<u16> {
+    let limits = cli::get();
+    let shallowest = cmp::max(layer.minzoom, limits.min_zoom);
+    let deepest = cmp::min(
+        layer.maxzoom.saturating_add(layer.overzoom),
+        limits.max_zoom,
+    );
+
+    shallowest..=deepest
+}
+
+// Tile of a single source, taken from the caches when possible
+pub async fn source_tile(
+    tiled: &cache::TileCache,
+    caching: &config::Caching,
+    user_agent: &str,
+    name: &str,
+    source: &config::Source,
+    given_xyz: &utils::XYZ,
+) -> Option<Vec<u8>> {
+    // Requests missing the same tile at once wait for a single fetch
+    let key = (name.to_string(), given_xyz.clone());
+    let entry = tiled
+        .entry(key)
+        .or_optionally_insert_with(async {
+            // Then the one on disk
+            if let Some(directory) = &caching.directory {
+                let stored = cache::disk_read(directory, caching.expiry, name, given_xyz).await;
+                telemetry::cache_lookup(cache::CACHE_DISK, stored.is_some());
+                if stored.is_some() {
+                    debug!(source = name, cache = "disk", "Source tile");
+                    return stored;
+                }
+            }
+
+            fetch_upstream(caching, user_agent, name, source, given_xyz).await
+        })
+        .await?;
+
+    // Only the request that filled it missed
+    let hit = !entry.is_fresh();
+    debug!(
+        source = name,
+        cache = if hit { "hit" } else { "miss" },
+        "Source tile"
+    );
+    telemetry::cache_lookup(cache::CACHE_TILE, hit);
+
+    Some(entry.into_value())
+}
+
+// Tile of a single source straight from upstream, kept in every cache
+pub async fn fetch_source_tile(
+    tiled: &cache::TileCache,
+    caching: &config::Caching,
+    user_agent: &str,
+    name: &str,
+    source: &config::Source,
+    given_xyz: &utils::XYZ,
+) -> Option<Vec<u8>> {
+    let fit = fetch_upstream(caching, user_agent, name, source, given_xyz).await?;
+    tiled
+        .insert((name.to_string(), given_xyz.clone()), fit.clone())
+        .await;
+
+    Some(fit)
+}
+
+// Tile of a single source from upstream, also kept on disk when there is one
+async fn fetch_upstream(
+    caching: &config::Caching,
+    user_agent: &str,
+    name: &str,
+    source: &config::Source,
+    given_xyz: &utils::XYZ,
+) -> Option<Vec<u8>> {
+    // Get 4 subtiles and merge them
+    let sub_tiles = utils::sub_tiles(source.quadrants(), given_xyz);
+    let quadrants = getter::get_quadrant_tiles(user_agent, name, source, &sub_tiles).await?;
+
+    // Transform quadrants into tiles
+    // Also resize if needed
+    let fit = paint::join_quadrant_tiles(&quadrants).await?;
+
+    // Cache them
+    if let Some(directory) = &caching.directory {
+        if let Err(error) = cache::disk_write(directory, name, given_xyz, &fit).await {
+            warn!(source = name, %error, "Source tile not stored");
+        }
+    }
+
+    Some(fit)
+}
+
+// Every available source of a layer along with how it is composed
+pub async fn layer_tiles<'layer>(
+    tiled: &cache::TileCache,
+    user_agent: &str,
+    config: &config::Config,
+    layer: &'layer config::Layer,
+    given_xyz: &utils::XYZ,
+) -> Vec<(&'layer config::Composite, Vec<u8>)> {
+    let mut tiles = Vec::new();
+
+    for composite in layer.sources.iter() {
+        let source = match config.sources.get(&composite.source) {
+            Some(source) => source,
+            None => continue,
+        };
+        let tile = source_tile(
+            tiled,
+            &config.cache,
+            user_agent,
+            &composite.source,
+            source,
+            given_xyz,
+        );
+        if let Some(tile) = tile.await {
+            tiles.push((composite, tile));
+        }
+    }
+
+    tiles
+}
+
+// Alerts of a cached area, fetched along with its surroundings when missing
+pub async fn cached_alerts(
+    cached: &cache::DataCache,
+    user_agent: &str,
+    cache_area: &utils::XYZ,
+) -> Arc<cache::Alerts> {
+    // Look for cache and use it if present
+    if let Some(something) = cached.get(cache_area).await {
+        debug!(cache = "hit", "Alerts");
+        telemetry::cache_lookup(cache::CACHE_DATA, true);
+        return something;
+    }
+    debug!(cache = "miss", "Alerts");
+    telemetry::cache_lookup(cache::CACHE_DATA, false);
+
+    // Grow it so alerts just outside of it are known too
+    let cache_spaced = utils::grow_pad(utils::TILE_OFFSET, cache_area);
+    let data = getter::get_jsons(user_agent, &cache_spaced).await;
+    let extracted = getter::alerts_extract(&data.unwrap_or_default());
+    let indexed = Arc::new(cache::index_alerts(extracted));
+    cached.insert(cache_area.clone(), indexed.clone()).await;
+
+    indexed
+}
+
+// Alerts inside an area, out of the same cached areas tiles use
+pub async fn plot_alerts(
+    cached: &cache::DataCache,
+    user_agent: &str,
+    config: &config::Config,
+    area: &utils::Plot,
+    z: u16,
+) -> Vec<getter::Alert> {
+    let area_zoom = cmp::min(z, config.cache.zoom);
+
+    // Fetch every cached area at once
+    let mut tasks = JoinSet::new();
+    for cache_area in utils::plot_tiles(area, area_zoom) {
+        let (cached, user_agent) = (cached.clone(), user_agent.to_string());
+        tasks.spawn(async move {
+            let indexed = cached_alerts(&cached, &user_agent, &cache_area).await;
+            (cache_area, indexed)
+        });
+    }
+
+    // Each cached area only gives its own alerts so none is found twice
+    let mut pings = Vec::new();
+    for (cache_area, indexed) in tasks.join_all().await {
+        let own = utils::grow_pad(0, &cache_area);
+        pings.extend(
+            cache::find_alerts(&indexed, area)
+                .into_iter()
+                .filter(|alert| utils::plot_contains(&own, &alert.position)),
+        );
+    }
+    getter::alerts_sort(&mut pings);
+
+    pings
+}
+
+// Alerts around a tile together with the area they are confined to
+pub async fn area_alerts(
+    cached: &cache::DataCache,
+    user_agent: &str,
+    config: &config::Config,
+    given_xyz: &utils::XYZ,
+) -> (Vec<getter::Alert>, utils::Plot) {
+    // Zoom out for a larger cached area
+    let cache_area = utils::zoom_scale(config.cache.zoom, given_xyz);
+    // Generic big area that we will actually use for painting
+    let pings_spaced = utils::grow_pad(utils::TILE_OFFSET, given_xyz);
+
+    // Extract only the needed area
+    let pings_chosen = cached_alerts(cached, user_agent, &cache_area).await;
+    let pings_area = cache::find_alerts(&pings_chosen, &pings_spaced);
+
+    (pings_area, pings_spaced)
+}
+
+// Alerts of a layer on their own tile, nothing when there are none
+async fn alerts_tile(
+    cached: &cache::DataCache,
+    user_agent: &str,
+    config: &config::Config,
+    layer: &config::Layer,
+    given_xyz: &utils::XYZ,
+) -> Option<RgbaImage> {
+    if !layer.alerts {
+        return None;
+    }
+    let (pings_area, pings_spaced) = area_alerts(cached, user_agent, config, given_xyz).await;
+
+    let started = Instant::now();
+    let tiles_alerts = paint::alerts_to_tile(&pings_area, &pings_spaced);
+    telemetry::render_stage("alerts", started.elapsed());
+
+    tiles_alerts
+}
+
+// Full tile of a layer, nothing when there was nothing to paint
+pub async fn tile(
+    cached: &cache::DataCache,
+    tiled: &cache::TileCache,
+    user_agent: &str,
+    config: &config::Config,
+    layer: &config::Layer,
+    given_xyz: &utils::XYZ,
+) -> Option<RgbaImage> {
+    // Fetch the sources and the alerts together
+    let bases = layer_tiles(tiled, user_agent, config, layer, given_xyz);
+    let alerts = alerts_tile(cached, user_agent, config, layer, given_xyz);
+    let (bases, tiles_alerts) = tokio::join!(bases, alerts);
+
+    // Skip compositing when there is nothing to join
+    if bases.is_empty() {
+        return tiles_alerts;
+    }
+
+    // Join all tiles
+    let started = Instant::now();
+    let joined = paint::join_tiles(&bases, tiles_alerts.as_ref());
+    telemetry::render_stage("join", started.elapsed());
+
+    Some(joined)
+}
+
+// Tile of a layer, its sources cut out of their deepest zoom when past it
+pub async fn layer_tile(
+    cached: &cache::DataCache,
+    tiled: &cache::TileCache,
+    user_agent: &str,
+    config: &config::Config,
+    layer: &config::Layer,
+    given_xyz: &utils::XYZ,
+) -> Option<RgbaImage> {
+    if given_xyz.z <= layer.maxzoom {
+        return tile(cached, tiled, user_agent, config, layer, given_xyz).await;
+    }
+
+    // Only the sources are zoomed into, alerts keep their size at any zoom
+    let base = base_tile(tiled, user_agent, config, layer, given_xyz);
+    let alerts = alerts_tile(cached, user_agent, config, layer, given_xyz);
+    match tokio::join!(base, alerts) {
+        (Some(mut base), Some(alerts)) => {
+            imageops::overlay(&mut base, &alerts, 0, 0);
+            Some(base)
+        }
+        (base, alerts) => base.or(alerts),
+    }
+}
+
+// Sources of a layer joined without alerts, cut out of its deepest zoom when past it
+pub async fn base_tile(
+    tiled: &cache::TileCache,
+    user_agent: &str,
+    config: &config::Config,
+    layer: &config::Layer,
+    given_xyz: &utils::XYZ,
+) -> Option<RgbaImage> {
+    let parent = utils::zoom_scale(layer.maxzoom, given_xyz);
+    let bases = layer_tiles(tiled, user_agent, config, layer, &parent).await;
+    if bases.is_empty() {
+        return None;
+    }
+
+    let joined = paint::join_tiles(&bases, None);
+    if parent == *given_xyz {
+        return Some(joined);
+    }
+
+    Some(paint::overzoom(&joined, &parent, given_xyz))
+}
@@ -0,0 +1,255 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::cache;
+use crate::cli;
+use crate::config;
+use crate::paint;
+use crate::render;
+use crate::utils;
+
+// Async
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::{self, MissedTickBehavior};
+
+// Cache
+use moka::future::Cache;
+
+// Logs
+use tracing::{info, warn};
+
+// Standard
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/*************
+ * Variables *
+ *************/
+
+// Tiles between progress lines
+const PROGRESS_EVERY: usize = 100;
+
+/*********
+ * Types *
+ *********/
+
+// Finished tiles by position
+pub type Rendered = Vec<(utils::XYZ, Vec<u8>)>;
+
+/***********
+ * Structs *
+ ***********/
+
+// What a single tile needs once spawned
+#[derive(Clone)]
+pub struct Job {
+    pub config: Arc<config::Config>,
+    pub caches: cache::Caches,
+    pub layer: String,
+    pub user_agent: String,
+    pub format: config::Format,
+    pub target: Target,
+}
+
+// Where the tiles go
+#[derive(Clone)]
+pub enum Target {
+    // Sources only, into the disk cache
+    Disk,
+    // Finished tiles as {z}/{x}/{y}.{format}
+    Directory(PathBuf),
+    // Finished tiles kept for an archive
+    Collect(Arc<Mutex<Rendered>>),
+}
+
+/*************
+ * Functions *
+ *************/
+
+// Fetch every tile of an area into the disk cache or an output directory
+pub async fn run(seed: &cli::Seed) -> Result<(), String> {
+    let config = config::get();
+
+    let target = match &seed.output {
+        Some(output) => Target::Directory(output.clone()),
+        None if config.cache.directory.is_some() => Target::Disk,
+        None => return Err("Nothing to keep tiles in, set cache.directory or --output".to_string()),
+    };
+
+    let job = Job::new(&config, &seed.area.layer, None, target)?;
+    walk(&seed.area, job).await
+}
+
+impl Job {
+    pub fn new(
+        config: &Arc<config::Config>,
+        layer: &str,
+        format: Option<config::Format>,
+        target: Target,
+    ) -> Result<Job, String> {
+        let chosen = config
+            .layers
+            .get(layer)
+            .ok_or_else(|| format!("Layer {} does not exist", layer))?;
+        let format = format
+            .or(chosen.output.formats.first().copied())
+            .unwrap_or(config::Format::Png);
+
+        // Memory caches only live as long as the command
+        Ok(Job {
+            config: Arc::clone(config),
+            caches: (Cache::new(cache::CACHE_MAX), Cache::new(cache::CACHE_MAX)),
+            layer: layer.to_string(),
+            user_agent: config.identity.user_agent(None),
+            format,
+            target,
+        })
+    }
+}
+
+// Zooms of an area the layer serves, source tiles past its deepest one are never read
+pub fn zooms(area: &cli::Area, layer: &config::Layer, target: &Target) -> Vec<u16> {
+    let deepest = match target {
+        Target::Disk => layer.maxzoom,
+        Target::Directory(_) | Target::Collect(_) => layer.maxzoom.saturating_add(layer.overzoom),
+    };
+    let (from, to) = area.zooms;
+    let zooms: Vec<u16> = (from..=to)
+        .filter(|z| (layer.minzoom..=deepest).contains(z))
+        .collect();
+
+    if zooms.len() < usize::from(to - from) + 1 {
+        warn!(
+            minzoom = layer.minzoom,
+            maxzoom = deepest,
+            "Zooms outside of the layer are skipped"
+        );
+    }
+
+    zooms
+}
+
+// Go through every tile of an area at a steady pace
+pub async fn walk(area: &cli::Area, job: Job) -> Result<(), String> {
+    if area.rate.is_nan() || area.rate <= 0.0 {
+        return Err("Rate must be above 0".to_string());
+    }
+
+    let zooms = zooms(area, &job.config.layers[&job.layer], &job.target);
+    let plot = utils::bbox_plot(area.bbox);
+    let total: usize = zooms
+        .iter()
+        .map(|z| utils::plot_tiles(&plot, *z).count())
+        .sum();
+    info!(total, layer = job.layer.as_str(), "Walking");
+
+    // Start tiles at a steady pace with a few at once
+    let mut pace = time::interval(Duration::from_secs_f64(1.0 / area.rate));
+    pace.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let permits = Arc::new(Semaphore::new(area.concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    let (mut done, mut failed) = (0, 0);
+
+    for z in zooms {
+        for position in utils::plot_tiles(&plot, z) {
+            pace.tick().await;
+            let permit = Arc::clone(&permits).acquire_owned().await.unwrap();
+            let job = job.clone();
+            tasks.spawn(async move {
+                let walked = walk_tile(&job, &position).await;
+                drop(permit);
+                walked
+            });
+
+            while let Some(walked) = tasks.try_join_next() {
+                tally(walked.unwrap_or(false), &mut done, &mut failed, total);
+            }
+        }
+    }
+    while let Some(walked) = tasks.join_next().await {
+        tally(walked.unwrap_or(false), &mut done, &mut failed, total);
+    }
+
+    info!(done, failed, "Walked");
+
+    if failed > 0 {
+        return Err(format!("{} of {} tiles failed", failed, total));
+    }
+
+    Ok(())
+}
+
+fn tally(walked: bool, done: &mut usize, failed: &mut usize, total: usize) {
+    *done += 1;
+    if !walked {
+        *failed += 1;
+    }
+    if done.is_multiple_of(PROGRESS_EVERY) {
+        info!(done = *done, failed = *failed, total, "Walking");
+    }
+}
+
+async fn walk_tile(job: &Job, position: &utils::XYZ) -> bool {
+    let layer = &job.config.layers[&job.layer];
+    let (cached, tiled) = &job.caches;
+
+    // Every source straight from upstream into the disk cache
+    if let Target::Disk = job.target {
+        let mut fetched = true;
+        for composite in layer.sources.iter() {
+            let source = match job.config.sources.get(&composite.source) {
+                Some(source) => source,
+                None => continue,
+            };
+            let tile = render::fetch_source_tile(
+                tiled,
+                &job.config.cache,
+                &job.user_agent,
+                &composite.source,
+                source,
+                position,
+            );
+            fetched &= tile.await.is_some();
+        }
+        return fetched;
+    }
+
+    // Finished tile, nothing is kept when there was nothing to paint
+    let painted =
+        render::layer_tile(cached, tiled, &job.user_agent, &job.config, layer, position).await;
+    let image = match painted {
+        Some(image) => image,
+        None => return true,
+    };
+    let bytes = paint::encode(&image, job.format, &layer.output);
+
+    match &job.target {
+        Target::Directory(output) => {
+            let path = output
+                .join(position.z.to_string())
+                .join(position.x.to_string())
+                .join(format!("{}.{}", position.y, job.format.extension()));
+
+            let written = async {
+                tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+                tokio::fs::write(&path, bytes).await
+            };
+            match written.await {
+                Ok(()) => true,
+                Err(error) => {
+                    warn!(path = %path.display(), %error, "Tile not written");
+                    false
+                }
+            }
+        }
+        Target::Collect(tiles) => {
+            tiles.lock().unwrap().push((position.clone(), bytes));
+            true
+        }
+        Target::Disk => true,
+    }
+}
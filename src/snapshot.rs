@@ -0,0 +1,364 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::cache;
+use crate::cli;
+use crate::config;
+use crate::error;
+use crate::paint;
+use crate::render;
+use crate::telemetry;
+use crate::tiles;
+use crate::utils;
+use crate::NAME;
+
+// HTTP
+use axum::{
+    body::Body,
+    extract::{rejection::QueryRejection, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+};
+use axum_extra::{headers::UserAgent, TypedHeader};
+
+// Data
+use serde::Deserialize;
+
+// Image
+use image::{imageops, RgbaImage};
+
+// Async
+use tokio::task::JoinSet;
+
+// Logs
+use tracing::info;
+
+// Standard
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Instant;
+
+/*************
+ * Variables *
+ *************/
+
+// Size used when none is given
+const SIZE_DEFAULT: utils::Raster = utils::Raster { x: 800, y: 600 };
+// Longest side of a snapshot
+const SIZE_MAX: u32 = 2048; // Pixels
+
+// Alerts this far outside still reach into the snapshot with their icons
+const ALERT_MARGIN: i64 = 128; // Pixels
+
+/***********
+ * Structs *
+ ***********/
+
+// Query
+#[derive(Deserialize)]
+pub struct Arguments {
+    // Either a centre and a zoom
+    center: Option<String>,
+    zoom: Option<u16>,
+    // Or an area to fit
+    bbox: Option<String>,
+    size: Option<String>,
+    layer: Option<String>,
+    format: Option<String>,
+}
+
+// Part of the world at a zoom, in pixels from its top left corner
+struct Viewport {
+    left: i64,
+    top: i64,
+    size: utils::Raster,
+    z: u16,
+}
+
+impl Viewport {
+    // Centred on a coordinate
+    fn centred(center: &utils::Coordinate, z: u16, size: utils::Raster) -> Self {
+        let (x, y) = utils::coordinate_to_world(center, z);
+        let tile = f64::from(utils::TILE_SIZE);
+
+        Viewport {
+            left: (x * tile).round() as i64 - i64::from(size.x / 2),
+            top: (y * tile).round() as i64 - i64::from(size.y / 2),
+            size,
+            z,
+        }
+    }
+
+    // Deepest zoom where the whole area still fits
+    fn fitted(area: &utils::Plot, size: utils::Raster, zooms: RangeInclusive<u16>) -> Self {
+        let (west, north) = utils::coordinate_to_world(&area.top, 0);
+        let (east, south) = utils::coordinate_to_world(&area.bottom, 0);
+        let tile = f64::from(utils::TILE_SIZE);
+
+        // Scale of the area on the lowest zoom
+        let scale_x = f64::from(size.x) / ((east - west) * tile);
+        let scale_y = f64::from(size.y) / ((south - north) * tile);
+        let fit = scale_x.min(scale_y).log2().floor();
+        let z = fit
+            .min(f64::from(*zooms.end()))
+            .max(f64::from(*zooms.start())) as u16;
+
+        let center = utils::world_to_coordinate((west + east) / 2.0, (north + south) / 2.0, 0);
+        Viewport::centred(&center, z, size)
+    }
+
+    // Tiles under the viewport along with where their corner lands on it
+    fn tiles(&self) -> Vec<(utils::XYZ, utils::Pixel)> {
+        let tile = i64::from(utils::TILE_SIZE);
+        let count = 1_i64 << self.z;
+        let right = self.left + i64::from(self.size.x) - 1;
+        let bottom = self.top + i64::from(self.size.y) - 1;
+
+        let mut tiles = Vec::new();
+        for y in self.top.div_euclid(tile)..=bottom.div_euclid(tile) {
+            // Nothing above or below the world
+            if !(0..count).contains(&y) {
+                continue;
+            }
+            for x in self.left.div_euclid(tile)..=right.div_euclid(tile) {
+                // Wrap around the antimeridian
+                let pane = utils::XYZ {
+                    x: x.rem_euclid(count) as u32,
+                    y: y as u32,
+                    z: self.z,
+                };
+                let corner = utils::Pixel {
+                    x: x * tile - self.left,
+                    y: y * tile - self.top,
+                };
+                tiles.push((pane, corner));
+            }
+        }
+
+        tiles
+    }
+
+    // Area covered, grown by a margin and kept inside the world
+    fn plot(&self, margin: i64) -> utils::Plot {
+        let tile = f64::from(utils::TILE_SIZE);
+        let world = (1_i64 << self.z) * i64::from(utils::TILE_SIZE);
+        let inside = |pixel: i64| pixel.clamp(0, world) as f64 / tile;
+        let left = inside(self.left - margin);
+        let top = inside(self.top - margin);
+        let right = inside(self.left + i64::from(self.size.x) + margin);
+        let bottom = inside(self.top + i64::from(self.size.y) + margin);
+
+        utils::Plot {
+            top: utils::world_to_coordinate(left, top, self.z),
+            bottom: utils::world_to_coordinate(right, bottom, self.z),
+        }
+    }
+
+    // Pixel of a coordinate on the viewport
+    fn locate(&self, item: &utils::Coordinate) -> utils::Pixel {
+        let (x, y) = utils::coordinate_to_world(item, self.z);
+        let tile = f64::from(utils::TILE_SIZE);
+
+        utils::Pixel {
+            x: (x * tile).floor() as i64 - self.left,
+            y: (y * tile).floor() as i64 - self.top,
+        }
+    }
+}
+
+/*************
+ * Functions *
+ *************/
+
+// Single image of an arbitrary part of a layer
+pub async fn snapshot(
+    State((cached, tiled)): State<cache::Caches>,
+    raw_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    arguments: Result<Query<Arguments>, QueryRejection>,
+) -> Result<Response, error::Failure> {
+    // Arguments
+    let Query(arguments) = arguments
+        .map_err(|rejection| error::Failure::bad_request("invalid_query", rejection.body_text()))?;
+    let size = match &arguments.size {
+        Some(size) => parse_size(size)
+            .map_err(|message| error::Failure::bad_request("invalid_size", message))?,
+        None => SIZE_DEFAULT,
+    };
+
+    // Find what was asked for
+    let config = config::get();
+    let client_agent = raw_agent.as_ref().map(|agent| agent.as_str());
+    let user_agent = config.identity.user_agent(client_agent);
+    let layer = arguments.layer.as_deref().unwrap_or(config::DEFAULT_LAYER);
+    let chosen = config.layers.get(layer).ok_or_else(|| {
+        error::Failure::not_found("unknown_layer", format!("Layer {} does not exist", layer))
+    })?;
+    let zooms = render::served_zooms(chosen);
+
+    // Either a centre or an area
+    let viewport = match (&arguments.center, arguments.zoom, &arguments.bbox) {
+        (Some(center), Some(zoom), None) => {
+            let center = parse_center(center)
+                .map_err(|message| error::Failure::bad_request("invalid_center", message))?;
+            if !zooms.contains(&zoom) {
+                return Err(error::Failure::not_found(
+                    "zoom_out_of_range",
+                    format!(
+                        "Layer {} is served from zoom {} to {}",
+                        layer,
+                        zooms.start(),
+                        zooms.end()
+                    ),
+                ));
+            }
+            Viewport::centred(&center, zoom, size)
+        }
+        (None, None, Some(bbox)) => {
+            let bbox = cli::parse_bbox(bbox)
+                .map_err(|message| error::Failure::bad_request("invalid_bbox", message))?;
+            let area = utils::bbox_plot(bbox);
+            Viewport::fitted(&area, size, zooms)
+        }
+        _ => {
+            return Err(error::Failure::bad_request(
+                "invalid_view",
+                "Either center and zoom or bbox is needed".to_string(),
+            ))
+        }
+    };
+
+    // Asked for or else what the client accepts
+    let format = match &arguments.format {
+        Some(format) => config::Format::from_extension(format).ok_or_else(|| {
+            error::Failure::bad_request("invalid_format", format!("{} is not a format", format))
+        })?,
+        None => {
+            let accept = headers
+                .get(header::ACCEPT)
+                .and_then(|value| value.to_str().ok());
+            tiles::negotiate(accept, &chosen.output.formats)
+        }
+    };
+
+    // Paint it
+    let started = Instant::now();
+    let painted = paint_viewport(&cached, &tiled, &user_agent, &config, layer, &viewport).await;
+    info!(
+        layer,
+        z = viewport.z,
+        width = viewport.size.x,
+        height = viewport.size.y,
+        format = format.mime(),
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "Snapshot rendered"
+    );
+    let bytes = paint::encode(&painted, format, &chosen.output);
+
+    // Response
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::SERVER, NAME)
+        .header(header::CONTENT_TYPE, format.mime())
+        .header(header::VARY, header::ACCEPT.as_str())
+        .body(Body::from(bytes))
+        .unwrap())
+}
+
+// Stitch the tiles of a layer and paint its alerts over them
+async fn paint_viewport(
+    cached: &cache::DataCache,
+    tiled: &cache::TileCache,
+    user_agent: &str,
+    config: &Arc<config::Config>,
+    layer: &str,
+    viewport: &Viewport,
+) -> RgbaImage {
+    let chosen = &config.layers[layer];
+
+    // Fetch every tile at once
+    let mut tasks = JoinSet::new();
+    for (pane, corner) in viewport.tiles() {
+        let (tiled, user_agent) = (tiled.clone(), user_agent.to_string());
+        let (config, layer) = (Arc::clone(config), layer.to_string());
+        tasks.spawn(async move {
+            let chosen = &config.layers[&layer];
+            let base = render::base_tile(&tiled, &user_agent, &config, chosen, &pane).await;
+            (corner, base)
+        });
+    }
+    let pings = async {
+        if chosen.alerts {
+            let spaced = viewport.plot(ALERT_MARGIN);
+            Some(render::plot_alerts(cached, user_agent, config, &spaced, viewport.z).await)
+        } else {
+            None
+        }
+    };
+    let (bases, pings) = tokio::join!(tasks.join_all(), pings);
+
+    // Stitch them together
+    let started = Instant::now();
+    let mut canvas = RgbaImage::new(viewport.size.x, viewport.size.y);
+    for (corner, base) in bases {
+        if let Some(base) = base {
+            imageops::overlay(&mut canvas, &base, corner.x, corner.y);
+        }
+    }
+    telemetry::render_stage("stitch", started.elapsed());
+
+    // Alerts right where they are
+    if let Some(pings) = pings {
+        let started = Instant::now();
+        paint::paint_alerts(&mut canvas, &pings, |position| viewport.locate(position));
+        telemetry::render_stage("alerts", started.elapsed());
+    }
+
+    canvas
+}
+
+// Width and height, such as 800x600
+fn parse_size(given: &str) -> Result<utils::Raster, String> {
+    let (width, height) = given
+        .split_once('x')
+        .ok_or_else(|| "size is given as WIDTHxHEIGHT".to_string())?;
+    let width: u32 = width
+        .trim()
+        .parse()
+        .map_err(|_| format!("{} is not a width", width))?;
+    let height: u32 = height
+        .trim()
+        .parse()
+        .map_err(|_| format!("{} is not a height", height))?;
+
+    if !(1..=SIZE_MAX).contains(&width) || !(1..=SIZE_MAX).contains(&height) {
+        return Err(format!("sides go from 1 to {} pixels", SIZE_MAX));
+    }
+
+    Ok(utils::Raster {
+        x: width,
+        y: height,
+    })
+}
+
+// Latitude and longitude
+fn parse_center(given: &str) -> Result<utils::Coordinate, String> {
+    let parts = given
+        .split(',')
+        .map(|part| {
+            part.trim()
+                .parse::<f64>()
+                .map_err(|error| error.to_string())
+        })
+        .collect::<Result<Vec<f64>, String>>()?;
+
+    match parts[..] {
+        [lat, lon] if (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon) => {
+            Ok(utils::Coordinate { lat, lon })
+        }
+        [_, _] => Err("latitude must be within 90 and longitude within 180".to_string()),
+        _ => Err("two numbers are needed".to_string()),
+    }
+}
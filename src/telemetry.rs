@@ -0,0 +1,121 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::cache;
+use crate::NAME;
+
+// HTTP
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+
+// Metrics
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+// Standard
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/*************
+ * Variables *
+ *************/
+
+// Upper bounds in seconds, from a cached tile to a slow upstream
+const BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+// Label of requests that matched no route
+const UNMATCHED: &str = "unmatched";
+
+// Recorder rendering the exposition
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/*************
+ * Functions *
+ *************/
+
+// Install the global recorder
+pub fn init() {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("seconds".to_string()), &BUCKETS)
+        .unwrap()
+        .install_recorder()
+        .unwrap();
+
+    HANDLE.set(handle).ok();
+}
+
+// Prometheus text exposition of everything recorded
+pub fn render() -> String {
+    HANDLE
+        .get()
+        .map(PrometheusHandle::render)
+        .unwrap_or_default()
+}
+
+// Prometheus exposition
+pub async fn metrics(State((cached, tiled)): State<cache::Caches>) -> Response {
+    // Entry counts are only known when asked for
+    cache_entries(cache::CACHE_DATA, cached.entry_count());
+    cache_entries(cache::CACHE_TILE, tiled.entry_count());
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::SERVER, NAME)
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(render()))
+        .unwrap()
+}
+
+// Count and time every request by the route it matched
+pub async fn track(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or(UNMATCHED.to_string());
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let status = response.status().as_u16().to_string();
+
+    counter!("mapper_requests_total", "route" => route.clone(), "status" => status).increment(1);
+    histogram!("mapper_request_seconds", "route" => route).record(started.elapsed());
+
+    response
+}
+
+// Lookup in one of the caches
+pub fn cache_lookup(cache: &'static str, hit: bool) {
+    let name = if hit {
+        "mapper_cache_hits_total"
+    } else {
+        "mapper_cache_misses_total"
+    };
+    counter!(name, "cache" => cache).increment(1);
+}
+
+pub fn cache_entries(cache: &'static str, entries: u64) {
+    gauge!("mapper_cache_entries", "cache" => cache).set(entries as f64);
+}
+
+// Answer of an upstream, by source and status
+pub fn upstream(source: &str, status: String, elapsed: Duration) {
+    let source = source.to_string();
+    counter!("mapper_upstream_responses_total", "source" => source.clone(), "status" => status)
+        .increment(1);
+    histogram!("mapper_upstream_seconds", "source" => source).record(elapsed);
+}
+
+// Time spent painting, by stage
+pub fn render_stage(stage: &'static str, elapsed: Duration) {
+    histogram!("mapper_render_seconds", "stage" => stage).record(elapsed);
+}
@@ -0,0 +1,120 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::cli;
+use crate::config;
+use crate::error;
+use crate::render;
+use crate::utils;
+
+// HTTP
+use axum::{
+    extract::{rejection::PathRejection, Path},
+    http::{header, HeaderMap},
+    response::Json,
+};
+
+// Data
+use serde::Serialize;
+
+/*************
+ * Variables *
+ *************/
+
+// Version of the specification that is followed
+const TILEJSON: &str = "3.0.0";
+
+/***********
+ * Structs *
+ ***********/
+
+// Description of a layer clients can set themselves up from
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TileJson {
+    tilejson: &'static str,
+    name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    attribution: String,
+    scheme: &'static str,
+    tiles: Vec<String>,
+    minzoom: u16,
+    maxzoom: u16,
+    // West, south, east and north in degrees
+    bounds: [f64; 4],
+    // Longitude, latitude and zoom
+    center: (f64, f64, u16),
+    tile_size: u32,
+    format: &'static str,
+}
+
+/*************
+ * Functions *
+ *************/
+
+// TileJSON of a layer, asked for as /{layer}.json
+pub async fn tilejson(
+    headers: HeaderMap,
+    path: Result<Path<String>, PathRejection>,
+) -> Result<Json<TileJson>, error::Failure> {
+    // Arguments
+    let Path(file) = path
+        .map_err(|rejection| error::Failure::bad_request("invalid_path", rejection.body_text()))?;
+    let layer = file.strip_suffix(".json").ok_or_else(|| {
+        error::Failure::not_found("not_found", format!("{} does not exist", file))
+    })?;
+
+    // Find what was asked for
+    let config = config::get();
+    let chosen = config.layers.get(layer).ok_or_else(|| {
+        error::Failure::not_found("unknown_layer", format!("Layer {} does not exist", layer))
+    })?;
+    let zooms = render::served_zooms(chosen);
+
+    // Tiles are pointed at in the preferred format
+    let format = chosen
+        .output
+        .formats
+        .first()
+        .copied()
+        .unwrap_or(config::Format::Png);
+    let template = format!(
+        "{}/{}/{{z}}/{{x}}/{{y}}.{}",
+        public_url(&headers),
+        layer,
+        format.extension()
+    );
+
+    let [west, south, east, north] = chosen.bounds;
+    Ok(Json(TileJson {
+        tilejson: TILEJSON,
+        name: layer.to_string(),
+        attribution: chosen.attribution.clone(),
+        scheme: "xyz",
+        tiles: vec![template],
+        minzoom: *zooms.start(),
+        maxzoom: *zooms.end(),
+        bounds: chosen.bounds,
+        center: ((west + east) / 2.0, (south + north) / 2.0, *zooms.start()),
+        tile_size: utils::TILE_SIZE,
+        format: format.extension(),
+    }))
+}
+
+// Address clients reach the server at, without a trailing slash
+pub fn public_url(headers: &HeaderMap) -> String {
+    if let Some(url) = &cli::get().public_url {
+        return url.trim_end_matches('/').to_string();
+    }
+
+    // Behind a proxy the forwarded headers are the ones clients used
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let scheme = header("x-forwarded-proto").unwrap_or("http");
+    let host = header("x-forwarded-host")
+        .or(header(header::HOST.as_str()))
+        .unwrap_or("localhost");
+
+    format!("{}://{}", scheme, host)
+}
@@ -0,0 +1,259 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::cache;
+use crate::config;
+use crate::error;
+use crate::paint;
+use crate::render;
+use crate::utils;
+use crate::NAME;
+
+// HTTP
+use axum::{
+    body::Body,
+    extract::{
+        rejection::{PathRejection, QueryRejection},
+        ConnectInfo, Path, Query, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+};
+use axum_extra::{headers::UserAgent, TypedHeader};
+
+// Data
+use serde::Deserialize;
+
+// Logs
+use tracing::info;
+
+// Standard
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/***********
+ * Structs *
+ ***********/
+
+// Query
+#[derive(Deserialize)]
+pub struct Arguments {
+    x: u32,
+    y: u32,
+    z: u16,
+    layer: Option<String>,
+}
+
+/************
+ * Handlers *
+ ************/
+
+// Basic
+pub async fn default(
+    State(caches): State<cache::Caches>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    raw_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    arguments: Result<Query<Arguments>, QueryRejection>,
+) -> Result<Response, error::Failure> {
+    // Arguments
+    let arguments = arguments
+        .map_err(|rejection| error::Failure::bad_request("invalid_query", rejection.body_text()))?;
+
+    // Convert inputs
+    let given_xyz = utils::XYZ {
+        x: arguments.x,
+        y: arguments.y,
+        z: arguments.z,
+    };
+    let layer = arguments.layer.as_deref().unwrap_or(config::DEFAULT_LAYER);
+
+    serve_tile(
+        &caches,
+        &addr,
+        raw_agent.as_deref(),
+        &headers,
+        layer,
+        &given_xyz,
+        None,
+    )
+    .await
+}
+
+// Layer with the path of a common XYZ server
+pub async fn layered(
+    State(caches): State<cache::Caches>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    raw_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    path: Result<Path<(String, u16, u32, String)>, PathRejection>,
+) -> Result<Response, error::Failure> {
+    // Arguments
+    let Path((layer, z, x, tile)) = path
+        .map_err(|rejection| error::Failure::bad_request("invalid_path", rejection.body_text()))?;
+
+    // The row can come with the format as its extension
+    let (y, format) = match tile.split_once('.') {
+        Some((y, extension)) => match config::Format::from_extension(extension) {
+            Some(format) => (y, Some(format)),
+            None => {
+                return Err(error::Failure::not_found(
+                    "unknown_format",
+                    format!("Format {} is not supported", extension),
+                ))
+            }
+        },
+        None => (tile.as_str(), None),
+    };
+    let y = y.parse().map_err(|_| {
+        error::Failure::bad_request("invalid_path", format!("Row {} is not a number", y))
+    })?;
+
+    // Convert inputs
+    let given_xyz = utils::XYZ { x, y, z };
+
+    serve_tile(
+        &caches,
+        &addr,
+        raw_agent.as_deref(),
+        &headers,
+        &layer,
+        &given_xyz,
+        format,
+    )
+    .await
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(client = %addr, layer = layer, x = given_xyz.x, y = given_xyz.y, z = given_xyz.z)
+)]
+pub async fn serve_tile(
+    (cached, tiled): &cache::Caches,
+    addr: &SocketAddr,
+    raw_agent: Option<&UserAgent>,
+    headers: &HeaderMap,
+    layer: &str,
+    given_xyz: &utils::XYZ,
+    format: Option<config::Format>,
+) -> Result<Response, error::Failure> {
+    // Client User Agent, if any
+    let client_agent = raw_agent.map(UserAgent::as_str);
+
+    // Verbose
+    info!(agent = client_agent.unwrap_or("-"), "Tile requested");
+
+    // Check the tile is real before anything else
+    if given_xyz.z > utils::MAX_ZOOM {
+        return Err(error::Failure::bad_request(
+            "invalid_tile",
+            format!("Zoom must be at most {}", utils::MAX_ZOOM),
+        ));
+    }
+    if !utils::tile_exists(given_xyz) {
+        return Err(error::Failure::bad_request(
+            "invalid_tile",
+            format!("Column and row must be below {}", 1_u64 << given_xyz.z),
+        ));
+    }
+
+    // Find what was asked for
+    let config = config::get();
+    let user_agent = config.identity.user_agent(client_agent);
+    let chosen = config.layers.get(layer).ok_or_else(|| {
+        error::Failure::not_found("unknown_layer", format!("Layer {} does not exist", layer))
+    })?;
+
+    // Check it is served by the layer and the instance
+    let zooms = render::served_zooms(chosen);
+    if !zooms.contains(&given_xyz.z) {
+        return Err(error::Failure::not_found(
+            "zoom_out_of_range",
+            format!(
+                "Layer {} is served from zoom {} to {}",
+                layer,
+                zooms.start(),
+                zooms.end()
+            ),
+        ));
+    }
+
+    // Extension wins over what the client accepts
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+    let format = format.unwrap_or_else(|| negotiate(accept, &chosen.output.formats));
+
+    // Join all tiles & extract its bytes
+    let started = Instant::now();
+    let tiles_joined =
+        render::layer_tile(cached, tiled, &user_agent, &config, chosen, given_xyz).await;
+    info!(
+        painted = tiles_joined.is_some(),
+        format = format.mime(),
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "Tile rendered"
+    );
+    let tiles_bytes = match tiles_joined {
+        Some(tiles_joined) => paint::encode(&tiles_joined, format, &chosen.output),
+        None if chosen.empty == config::Empty::NoContent => {
+            return Ok(empty(StatusCode::NO_CONTENT))
+        }
+        None => paint::encode_empty(format, &chosen.output),
+    };
+
+    // Response
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::SERVER, NAME)
+        .header(header::CONTENT_TYPE, format.mime())
+        .header(header::VARY, header::ACCEPT.as_str())
+        .body(Body::from(tiles_bytes))
+        .unwrap())
+}
+
+// First preferred format the client is willing to take
+pub fn negotiate(accept: Option<&str>, formats: &[config::Format]) -> config::Format {
+    let fallback = formats.first().copied().unwrap_or(config::Format::Png);
+
+    // Anything goes
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return fallback,
+    };
+
+    // Media ranges with a quality above zero
+    let ranges: Vec<&str> = accept
+        .split(',')
+        .filter_map(|part| {
+            let mut pieces = part.split(';');
+            let range = pieces.next()?.trim();
+            let quality = pieces
+                .find_map(|piece| piece.trim().strip_prefix("q="))
+                .and_then(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (quality > 0.0).then_some(range)
+        })
+        .collect();
+
+    formats
+        .iter()
+        .copied()
+        .find(|format| {
+            ranges
+                .iter()
+                .any(|range| *range == format.mime() || *range == "image/*" || *range == "*/*")
+        })
+        .unwrap_or(fallback)
+}
+
+// Response without a body
+fn empty(status: StatusCode) -> Response {
+    Response::builder()
+        .status(status)
+        .header(header::SERVER, NAME)
+        .body(Body::empty())
+        .unwrap()
+}
@@ -9,6 +9,7 @@ use std::f64::consts;
  * Structures *
  **************/
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Hash, Clone, Eq, PartialEq)]
 pub struct XYZ {
     pub x: u32,
@@ -22,6 +23,17 @@ pub struct Coordinate {
     pub lon: f64,
 }
 
+// Web Mercator metres
+pub struct Metres {
+    pub x: f64,
+    pub y: f64,
+}
+
+pub struct Extent {
+    pub top: Metres,
+    pub bottom: Metres,
+}
+
 pub struct Plot {
     pub top: Coordinate,
     pub bottom: Coordinate,
@@ -32,6 +44,12 @@ pub struct Raster {
     pub y: u32,
 }
 
+// Position on a canvas that can fall outside of it
+pub struct Pixel {
+    pub x: i64,
+    pub y: i64,
+}
+
 pub struct Ratios {
     pub x: f64,
     pub y: f64,
@@ -44,6 +62,9 @@ pub struct Ratios {
 // Tiling
 pub const TILE_SIZE: u32 = 512; // Pixels
 
+// Deepest zoom that is accepted at all
+pub const MAX_ZOOM: u16 = 30;
+
 // Offsets around tile
 pub const TILE_OFFSET: u32 = 1;
 pub const TILE_OFFSET_LENGTH: u32 = (TILE_OFFSET * 2) + 1;
@@ -54,6 +75,12 @@ pub const TILE_INFLATED: u32 = TILE_OFFSET_LENGTH * TILE_SIZE;
 // Start of original tile in an offset situation
 pub const TILE_ORIGINAL_START: u32 = TILE_OFFSET * TILE_SIZE;
 
+// Latitude where Web Mercator tiles end
+pub const MAX_LATITUDE: f64 = 85.051_128_779_806_59; // Degrees
+
+// Sphere used by Web Mercator
+pub const EARTH_RADIUS: f64 = 6378137.0; // Metres
+
 // Location of the point on an icon
 const ICON_POINT: Ratios = Ratios { x: 0.5, y: 1.0 };
 
@@ -69,7 +96,7 @@ pub fn zoom_scale(new_z: u16, pane: &XYZ) -> XYZ {
     }
 
     // Get Correlation
-    let correlation = i32::from(i32::from(pane.z) - i32::from(new_z));
+    let correlation = i32::from(pane.z) - i32::from(new_z);
 
     // New Coordinates
     let new_x = f64::from(pane.x) / 2_f64.powi(correlation);
@@ -82,14 +109,14 @@ pub fn zoom_scale(new_z: u16, pane: &XYZ) -> XYZ {
     }
 }
 
-pub fn sub_tiles(pane: &XYZ) -> Vec<XYZ> {
+pub fn sub_tiles(deepest: u16, pane: &XYZ) -> Vec<XYZ> {
     // Create predefined vector
     let mut tiles: Vec<XYZ> = Vec::new();
 
     let new_z = pane.z + 1;
 
     // Check if we are at maximum
-    if new_z >= 15 {
+    if new_z > deepest {
         tiles.push(pane.clone());
         return tiles;
     }
@@ -100,17 +127,17 @@ pub fn sub_tiles(pane: &XYZ) -> Vec<XYZ> {
 
     // Insert tiles
     tiles.push(XYZ {
-        x: corner_x + 0,
-        y: corner_y + 0,
+        x: corner_x,
+        y: corner_y,
         z: new_z,
     });
     tiles.push(XYZ {
         x: corner_x + 1,
-        y: corner_y + 0,
+        y: corner_y,
         z: new_z,
     });
     tiles.push(XYZ {
-        x: corner_x + 0,
+        x: corner_x,
         y: corner_y + 1,
         z: new_z,
     });
@@ -123,19 +150,56 @@ pub fn sub_tiles(pane: &XYZ) -> Vec<XYZ> {
     tiles
 }
 
+// Whether the tile is inside the world
+pub fn tile_exists(pane: &XYZ) -> bool {
+    if pane.z > MAX_ZOOM {
+        return false;
+    }
+
+    let size = 1_u64 << pane.z;
+    u64::from(pane.x) < size && u64::from(pane.y) < size
+}
+
+// Row counted from the bottom as used by TMS
+pub fn tms_y(pane: &XYZ) -> u32 {
+    let last = (1_u64 << cmp::min(pane.z, MAX_ZOOM)) - 1;
+    last.saturating_sub(u64::from(pane.y)) as u32
+}
+
+// Bing style single key of a tile
+pub fn quadkey(pane: &XYZ) -> String {
+    let mut key = String::with_capacity(usize::from(pane.z));
+
+    for level in (1..=pane.z).rev() {
+        let mask = 1 << (level - 1);
+        let mut digit = 0;
+        if pane.x & mask != 0 {
+            digit += 1;
+        }
+        if pane.y & mask != 0 {
+            digit += 2;
+        }
+        key.push(char::from(b'0' + digit));
+    }
+
+    key
+}
+
 // XYZ -> Lat & Lon
 pub fn xyz_to_coordinate(pane: &XYZ) -> Coordinate {
+    world_to_coordinate(f64::from(pane.x), f64::from(pane.y), pane.z)
+}
+
+// Fractional tile position -> Lat & Lon
+pub fn world_to_coordinate(x: f64, y: f64, z: u16) -> Coordinate {
     // Size
-    let n = 2_f64.powi(i32::from(pane.z));
+    let n = 2_f64.powi(i32::from(z));
 
     // Longitude
-    let longitude = ((f64::from(pane.x) / n) * 360.0) - 180.0;
+    let longitude = ((x / n) * 360.0) - 180.0;
 
     // Latitude
-    let latitude = (180.0 / consts::PI)
-        * (consts::PI * (1.0 - 2.0 * (f64::from(pane.y) / n)))
-            .sinh()
-            .atan();
+    let latitude = (180.0 / consts::PI) * (consts::PI * (1.0 - 2.0 * (y / n))).sinh().atan();
 
     // Return
     Coordinate {
@@ -144,6 +208,66 @@ pub fn xyz_to_coordinate(pane: &XYZ) -> Coordinate {
     }
 }
 
+// Lat & Lon -> Fractional tile position
+pub fn coordinate_to_world(item: &Coordinate, z: u16) -> (f64, f64) {
+    // Size
+    let n = 2_f64.powi(i32::from(z));
+
+    // Column
+    let x = ((item.lon + 180.0) / 360.0) * n;
+
+    // Row
+    let latitude = item.lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    let y = (1.0 - (latitude.tan() + (1.0 / latitude.cos())).ln() / consts::PI) / 2.0 * n;
+
+    (x, y)
+}
+
+// Lat & Lon -> XYZ containing it
+pub fn coordinate_to_xyz(item: &Coordinate, z: u16) -> XYZ {
+    let last = 2_f64.powi(i32::from(z)) - 1.0;
+    let (x, y) = coordinate_to_world(item, z);
+
+    XYZ {
+        x: x.floor().clamp(0.0, last) as u32,
+        y: y.floor().clamp(0.0, last) as u32,
+        z,
+    }
+}
+
+// Every tile of a zoom inside an area
+pub fn plot_tiles(area: &Plot, z: u16) -> impl Iterator<Item = XYZ> {
+    let top = coordinate_to_xyz(&area.top, z);
+    let bottom = coordinate_to_xyz(&area.bottom, z);
+
+    (top.x..=bottom.x).flat_map(move |x| (top.y..=bottom.y).map(move |y| XYZ { x, y, z }))
+}
+
+// Lat & Lon -> EPSG:3857
+pub fn coordinate_to_mercator(item: &Coordinate) -> Metres {
+    Metres {
+        x: EARTH_RADIUS * item.lon.to_radians(),
+        y: EARTH_RADIUS
+            * (consts::FRAC_PI_4 + (item.lat.to_radians() / 2.0))
+                .tan()
+                .ln(),
+    }
+}
+
+// XYZ -> EPSG:3857 bounds of the whole tile
+pub fn xyz_to_mercator(pane: &XYZ) -> Extent {
+    let bottom = XYZ {
+        x: pane.x + 1,
+        y: pane.y + 1,
+        z: pane.z,
+    };
+
+    Extent {
+        top: coordinate_to_mercator(&xyz_to_coordinate(pane)),
+        bottom: coordinate_to_mercator(&xyz_to_coordinate(&bottom)),
+    }
+}
+
 // Translate coordinates
 pub fn coordinates_confine(item: &Coordinate, confine: &Plot, dest: &Raster) -> Raster {
     // Get ratios
@@ -161,39 +285,48 @@ pub fn coordinates_confine(item: &Coordinate, confine: &Plot, dest: &Raster) ->
 }
 
 // Fix for images to represent a centre
-pub fn translate_edge(dimensions: &Raster, position: &Raster) -> Raster {
+pub fn translate_edge(dimensions: &Raster, position: &Pixel) -> Pixel {
     // Offsets
-    let offset_x: u32 = (f64::from(dimensions.x) * ICON_POINT.x) as u32;
-    let offset_y: u32 = (f64::from(dimensions.y) * ICON_POINT.y) as u32;
-
-    // Translate
-    let translated_x: u32 = if position.x > offset_x {
-        position.x - offset_x
-    } else {
-        0
-    };
-    let translated_y: u32 = if position.y > offset_y {
-        position.y - offset_y
-    } else {
-        0
-    };
+    let offset_x = (f64::from(dimensions.x) * ICON_POINT.x) as i64;
+    let offset_y = (f64::from(dimensions.y) * ICON_POINT.y) as i64;
 
-    Raster {
-        x: translated_x,
-        y: translated_y,
+    Pixel {
+        x: position.x - offset_x,
+        y: position.y - offset_y,
+    }
+}
+
+// Area of a west, south, east and north bounding box
+pub fn bbox_plot([west, south, east, north]: [f64; 4]) -> Plot {
+    Plot {
+        top: Coordinate {
+            lat: north,
+            lon: west,
+        },
+        bottom: Coordinate {
+            lat: south,
+            lon: east,
+        },
     }
 }
 
+// Whether a coordinate is inside an area, the east and south edges belong to the neighbours
+pub fn plot_contains(area: &Plot, item: &Coordinate) -> bool {
+    (area.top.lon..area.bottom.lon).contains(&item.lon)
+        && item.lat > area.bottom.lat
+        && item.lat <= area.top.lat
+}
+
 // Grow a pad by factors
 pub fn grow_pad(offset: u32, pane: &XYZ) -> Plot {
-    let min_tile = 0_i32;
-    let max_tile = 2_u32.pow(u32::from(pane.z));
+    let min_tile = 0_i64;
+    let max_tile = 1_i64 << cmp::min(pane.z, MAX_ZOOM);
 
     // Check boundaries
-    let top_x = cmp::max(min_tile, (pane.x as i32) - (offset as i32));
-    let top_y = cmp::max(min_tile, (pane.y as i32) - (offset as i32));
-    let bottom_x = cmp::min(max_tile, (pane.x + offset) as u32 + 1);
-    let bottom_y = cmp::min(max_tile, (pane.y + offset) as u32 + 1);
+    let top_x = cmp::max(min_tile, i64::from(pane.x) - i64::from(offset));
+    let top_y = cmp::max(min_tile, i64::from(pane.y) - i64::from(offset));
+    let bottom_x = cmp::min(max_tile, i64::from(pane.x) + i64::from(offset) + 1);
+    let bottom_y = cmp::min(max_tile, i64::from(pane.y) + i64::from(offset) + 1);
 
     let top = XYZ {
         x: top_x as u32,
@@ -211,3 +344,77 @@ pub fn grow_pad(offset: u32, pane: &XYZ) -> Plot {
         bottom: xyz_to_coordinate(&bottom),
     }
 }
+
+/*********
+ * Tests *
+ *********/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xyz(z: u16, x: u32, y: u32) -> XYZ {
+        XYZ { x, y, z }
+    }
+
+    #[test]
+    fn quadkey_matches_bing() {
+        assert_eq!(quadkey(&xyz(0, 0, 0)), "");
+        assert_eq!(quadkey(&xyz(1, 0, 0)), "0");
+        assert_eq!(quadkey(&xyz(1, 1, 0)), "1");
+        assert_eq!(quadkey(&xyz(1, 0, 1)), "2");
+        assert_eq!(quadkey(&xyz(1, 1, 1)), "3");
+        assert_eq!(quadkey(&xyz(3, 3, 5)), "213");
+        assert_eq!(quadkey(&xyz(4, 15, 0)), "1111");
+    }
+
+    #[test]
+    fn plot_contains_shares_edges_once() {
+        let area = bbox_plot([0.0, 0.0, 10.0, 10.0]);
+        let at = |lon, lat| plot_contains(&area, &Coordinate { lat, lon });
+
+        assert!(at(0.0, 10.0));
+        assert!(at(5.0, 5.0));
+        assert!(!at(10.0, 5.0));
+        assert!(!at(5.0, 0.0));
+    }
+
+    #[test]
+    fn tile_exists_at_the_deepest_zooms() {
+        let last = (1_u32 << 30) - 1;
+        assert!(tile_exists(&xyz(30, last, last)));
+        assert!(!tile_exists(&xyz(30, last + 1, 0)));
+        assert!(!tile_exists(&xyz(30, 0, last + 1)));
+        assert!(!tile_exists(&xyz(31, (1 << 31) - 1, 0)));
+        assert!(!tile_exists(&xyz(31, 1 << 31, 1 << 31)));
+        assert!(!tile_exists(&xyz(31, u32::MAX, u32::MAX)));
+    }
+
+    #[test]
+    fn grow_pad_stops_at_the_last_column_and_row() {
+        for z in [2, 30] {
+            let last = (1_u32 << z) - 1;
+            let area = grow_pad(TILE_OFFSET, &xyz(z, last, last));
+            assert_eq!(area.bottom.lon, 180.0);
+            assert!((area.bottom.lat + MAX_LATITUDE).abs() < 1e-9);
+            assert!(area.top.lon < area.bottom.lon);
+            assert!(area.top.lat > area.bottom.lat);
+        }
+
+        let area = grow_pad(u32::MAX, &xyz(30, u32::MAX, u32::MAX));
+        assert_eq!(area.top.lon, -180.0);
+        assert_eq!(area.bottom.lon, 180.0);
+    }
+
+    #[test]
+    fn tms_y_flips_rows() {
+        assert_eq!(tms_y(&xyz(0, 0, 0)), 0);
+        assert_eq!(tms_y(&xyz(1, 0, 0)), 1);
+        assert_eq!(tms_y(&xyz(1, 0, 1)), 0);
+        assert_eq!(tms_y(&xyz(3, 0, 2)), 5);
+        assert_eq!(tms_y(&xyz(10, 0, 0)), 1023);
+        assert_eq!(tms_y(&xyz(10, 0, 1023)), 0);
+        assert_eq!(tms_y(&xyz(30, 0, 0)), (1 << 30) - 1);
+        assert_eq!(tms_y(&xyz(31, 0, u32::MAX)), 0);
+    }
+}
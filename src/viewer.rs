@@ -0,0 +1,163 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::cache;
+use crate::cli;
+use crate::config;
+use crate::cross;
+use crate::error;
+use crate::render;
+use crate::utils;
+use crate::NAME;
+
+// HTTP
+use axum::{
+    extract::{rejection::QueryRejection, Query, State},
+    http::header,
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use axum_extra::{headers::UserAgent, TypedHeader};
+
+// Data
+use serde::{Deserialize, Serialize};
+
+// Standard
+use std::cmp;
+
+/*************
+ * Variables *
+ *************/
+
+// Page and what it loads, bundled with the binary
+const PAGE: &str = include_str!("viewer/index.html");
+const SCRIPT: &str = include_str!("viewer/viewer.js");
+const STYLE: &str = include_str!("viewer/viewer.css");
+
+// Cached areas a single request for alerts can go through
+const AREAS_MAX: u64 = 64;
+
+/***********
+ * Structs *
+ ***********/
+
+// Query
+#[derive(Deserialize)]
+pub struct Area {
+    bbox: String,
+    zoom: u16,
+}
+
+#[derive(Deserialize)]
+pub struct Icon {
+    #[serde(rename = "type")]
+    main_type: String,
+    #[serde(default)]
+    subtype: String,
+}
+
+// Alert as the page inspects it
+#[derive(Serialize)]
+pub struct Pin {
+    #[serde(rename = "type")]
+    main_type: String,
+    subtype: String,
+    lat: f64,
+    lon: f64,
+    // Milliseconds since epoch of when it was reported
+    published: Option<i64>,
+    severity: i32,
+}
+
+/*************
+ * Functions *
+ *************/
+
+pub fn routes() -> Router<cache::Caches> {
+    Router::new()
+        .route("/", get(page))
+        .route("/viewer.js", get(script))
+        .route("/viewer.css", get(style))
+        .route("/layers", get(layers))
+        .route("/alerts", get(alerts))
+        .route("/icon", get(icon))
+}
+
+// Bundled files
+pub async fn page() -> Response {
+    asset("text/html; charset=utf-8", PAGE.as_bytes())
+}
+
+async fn script() -> Response {
+    asset("text/javascript; charset=utf-8", SCRIPT.as_bytes())
+}
+
+async fn style() -> Response {
+    asset("text/css; charset=utf-8", STYLE.as_bytes())
+}
+
+fn asset(mime: &'static str, body: &'static [u8]) -> Response {
+    ([(header::SERVER, NAME), (header::CONTENT_TYPE, mime)], body).into_response()
+}
+
+// Names of the served layers
+async fn layers() -> Json<Vec<String>> {
+    Json(config::get().layers.keys().cloned().collect())
+}
+
+// Icon painted for a type and subtype
+async fn icon(arguments: Result<Query<Icon>, QueryRejection>) -> Result<Response, error::Failure> {
+    let Query(arguments) = arguments
+        .map_err(|rejection| error::Failure::bad_request("invalid_query", rejection.body_text()))?;
+    let bytes = cross::find_alert_asset(&arguments.main_type, &arguments.subtype);
+
+    Ok(asset("image/png", bytes))
+}
+
+// Alerts of an area seen at a zoom
+async fn alerts(
+    State((cached, _)): State<cache::Caches>,
+    raw_agent: Option<TypedHeader<UserAgent>>,
+    arguments: Result<Query<Area>, QueryRejection>,
+) -> Result<Json<Vec<Pin>>, error::Failure> {
+    // Arguments
+    let Query(arguments) = arguments
+        .map_err(|rejection| error::Failure::bad_request("invalid_query", rejection.body_text()))?;
+    let bbox = cli::parse_bbox(&arguments.bbox)
+        .map_err(|message| error::Failure::bad_request("invalid_bbox", message))?;
+    let area = utils::bbox_plot(bbox);
+
+    // Keep away from fetching half of the world at once
+    let config = config::get();
+    let area_zoom = cmp::min(arguments.zoom, config.cache.zoom);
+    let top = utils::coordinate_to_xyz(&area.top, area_zoom);
+    let bottom = utils::coordinate_to_xyz(&area.bottom, area_zoom);
+    let areas = u64::from(bottom.x - top.x + 1) * u64::from(bottom.y - top.y + 1);
+    if areas > AREAS_MAX {
+        return Err(error::Failure::bad_request(
+            "area_too_large",
+            format!("Area spans {} cached areas, at most {}", areas, AREAS_MAX),
+        ));
+    }
+
+    // Found alerts along with their rank
+    let client_agent = raw_agent.as_ref().map(|agent| agent.as_str());
+    let user_agent = config.identity.user_agent(client_agent);
+    let found = render::plot_alerts(&cached, &user_agent, &config, &area, arguments.zoom).await;
+    let pins = found
+        .into_iter()
+        .map(|alert| Pin {
+            severity: cross::find_alert_severity(&alert.icon, &alert.subicon, &config.severity),
+            main_type: alert.icon,
+            subtype: alert.subicon,
+            lat: alert.position.lat,
+            lon: alert.position.lon,
+            published: alert.published,
+        })
+        .collect();
+
+    Ok(Json(pins))
+}
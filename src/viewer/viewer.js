@@ -0,0 +1,446 @@
+// Small slippy map to preview the layers and inspect their alerts, everything comes from this server
+(() => {
+  "use strict";
+
+  const MAX_LATITUDE = 85.0511287798066;
+  // Ranks from which markers are coloured as more serious
+  const MEDIUM = 30;
+  const HIGH = 50;
+
+  const map = document.getElementById("map");
+  const tiles = document.getElementById("tiles");
+  const pins = document.getElementById("pins");
+  const popup = document.getElementById("popup");
+  const attribution = document.getElementById("attribution");
+  const layerSelect = document.getElementById("layer");
+  const zoomLevel = document.getElementById("zoom-level");
+  const status = document.getElementById("status");
+  const showPins = document.getElementById("show-pins");
+  const severity = document.getElementById("severity");
+  const severityValue = document.getElementById("severity-value");
+  const types = document.getElementById("types");
+  const count = document.getElementById("count");
+
+  // Centre in world pixels of the current zoom
+  const view = { x: 0, y: 0, z: 2 };
+  let layer = null;
+  let alerts = [];
+  const hidden = new Set();
+  const images = new Map();
+  let opened = null;
+  let fetching = null;
+  let waiting = null;
+
+  /**************
+   * Projection *
+   **************/
+
+  const tileSize = () => (layer ? layer.tileSize : 512);
+  const worldSize = (z) => tileSize() * 2 ** z;
+
+  function project(lat, lon, z) {
+    const size = worldSize(z);
+    const sine = Math.sin((Math.max(-MAX_LATITUDE, Math.min(MAX_LATITUDE, lat)) * Math.PI) / 180);
+    return {
+      x: ((lon + 180) / 360) * size,
+      y: (0.5 - Math.log((1 + sine) / (1 - sine)) / (4 * Math.PI)) * size,
+    };
+  }
+
+  function unproject(x, y, z) {
+    const size = worldSize(z);
+    const n = Math.PI - (2 * Math.PI * y) / size;
+    return {
+      lat: (180 / Math.PI) * Math.atan(Math.sinh(n)),
+      lon: (x / size) * 360 - 180,
+    };
+  }
+
+  // Top left corner of the map in world pixels
+  function corner() {
+    return {
+      left: Math.round(view.x - map.clientWidth / 2),
+      top: Math.round(view.y - map.clientHeight / 2),
+    };
+  }
+
+  // Keep the centre inside the world, wrapping around the antimeridian
+  function confine() {
+    const size = worldSize(view.z);
+    view.x = ((view.x % size) + size) % size;
+    view.y = Math.max(0, Math.min(size, view.y));
+  }
+
+  /*********
+   * Tiles *
+   *********/
+
+  function drawTiles() {
+    if (!layer) {
+      return;
+    }
+    const size = tileSize();
+    const count = 2 ** view.z;
+    const { left, top } = corner();
+    const used = new Set();
+
+    for (let y = Math.floor(top / size); y <= Math.floor((top + map.clientHeight - 1) / size); y++) {
+      if (y < 0 || y >= count) {
+        continue;
+      }
+      for (let x = Math.floor(left / size); x <= Math.floor((left + map.clientWidth - 1) / size); x++) {
+        const wrapped = ((x % count) + count) % count;
+        const key = `${view.z}/${x}/${y}`;
+        used.add(key);
+
+        let image = images.get(key);
+        if (!image) {
+          image = new Image(size, size);
+          image.alt = "";
+          image.src = layer.tiles[0]
+            .replace("{z}", view.z)
+            .replace("{x}", wrapped)
+            .replace("{y}", y);
+          images.set(key, image);
+          tiles.appendChild(image);
+        }
+        image.style.transform = `translate(${x * size - left}px, ${y * size - top}px)`;
+      }
+    }
+
+    // Drop what went out of sight
+    for (const [key, image] of images) {
+      if (!used.has(key)) {
+        image.remove();
+        images.delete(key);
+      }
+    }
+  }
+
+  function clearTiles() {
+    tiles.replaceChildren();
+    images.clear();
+  }
+
+  /**********
+   * Alerts *
+   **********/
+
+  function visible(alert) {
+    return !hidden.has(alert.type) && alert.severity >= Number(severity.value);
+  }
+
+  // Copy of a point closest to the centre, so markers follow the wrapped tiles
+  function place(alert) {
+    const size = worldSize(view.z);
+    const point = project(alert.lat, alert.lon, view.z);
+    point.x += Math.round((view.x - point.x) / size) * size;
+    const { left, top } = corner();
+    return { x: point.x - left, y: point.y - top };
+  }
+
+  function drawPins() {
+    pins.replaceChildren();
+    const shown = alerts.filter(visible);
+    count.textContent = `${shown.length} of ${alerts.length} alerts`;
+    if (!showPins.checked) {
+      return;
+    }
+
+    for (const alert of shown) {
+      const { x, y } = place(alert);
+      if (x < -10 || y < -10 || x > map.clientWidth + 10 || y > map.clientHeight + 10) {
+        continue;
+      }
+      const pin = document.createElement("div");
+      pin.className = `pin ${alert.severity >= HIGH ? "high" : alert.severity >= MEDIUM ? "medium" : "low"}`;
+      pin.style.left = `${x}px`;
+      pin.style.top = `${y}px`;
+      pin.title = alert.subtype || alert.type;
+      pin.addEventListener("pointerdown", (event) => event.stopPropagation());
+      pin.addEventListener("click", () => openPopup(alert));
+      pins.appendChild(pin);
+    }
+  }
+
+  function drawTypes() {
+    const known = [...new Set(alerts.map((alert) => alert.type))].sort();
+    types.replaceChildren();
+    for (const type of known) {
+      const label = document.createElement("label");
+      const box = document.createElement("input");
+      box.type = "checkbox";
+      box.checked = !hidden.has(type);
+      box.addEventListener("change", () => {
+        if (box.checked) {
+          hidden.delete(type);
+        } else {
+          hidden.add(type);
+        }
+        closePopup();
+        drawPins();
+      });
+      const total = alerts.filter((alert) => alert.type === type).length;
+      label.append(box, ` ${type} (${total})`);
+      types.appendChild(label);
+    }
+  }
+
+  async function loadAlerts() {
+    if (!layer) {
+      return;
+    }
+    const { left, top } = corner();
+    const size = worldSize(view.z);
+    const northWest = unproject(Math.max(0, left), Math.max(0, top), view.z);
+    const southEast = unproject(
+      Math.min(size, left + map.clientWidth),
+      Math.min(size, top + map.clientHeight),
+      view.z,
+    );
+    const bbox = [northWest.lon, southEast.lat, southEast.lon, northWest.lat]
+      .map((degrees) => degrees.toFixed(6))
+      .join(",");
+
+    // Only the last request counts
+    if (fetching) {
+      fetching.abort();
+    }
+    fetching = new AbortController();
+    try {
+      const response = await fetch(`/viewer/alerts?bbox=${bbox}&zoom=${view.z}`, {
+        signal: fetching.signal,
+      });
+      const body = await response.json();
+      if (!response.ok) {
+        alerts = [];
+        status.textContent = body.error === "area_too_large" ? "Zoom in to see alerts" : body.message;
+      } else {
+        alerts = body;
+        status.textContent = "";
+      }
+    } catch (error) {
+      if (error.name === "AbortError") {
+        return;
+      }
+      alerts = [];
+      status.textContent = "Alerts could not be loaded";
+    }
+    drawTypes();
+    drawPins();
+  }
+
+  /*********
+   * Popup *
+   *********/
+
+  function openPopup(alert) {
+    const icon = new Image();
+    icon.alt = "";
+    icon.src = `/viewer/icon?type=${encodeURIComponent(alert.type)}&subtype=${encodeURIComponent(alert.subtype)}`;
+
+    const details = document.createElement("dl");
+    const rows = [
+      ["Type", alert.type],
+      ["Subtype", alert.subtype || "-"],
+      ["Severity", alert.severity],
+      ["Position", `${alert.lat.toFixed(5)}, ${alert.lon.toFixed(5)}`],
+      ["Reported", reported(alert.published)],
+    ];
+    for (const [term, description] of rows) {
+      const dt = document.createElement("dt");
+      const dd = document.createElement("dd");
+      dt.textContent = term;
+      dd.textContent = description;
+      details.append(dt, dd);
+    }
+
+    popup.replaceChildren(icon, details);
+    opened = alert;
+    popup.hidden = false;
+    movePopup();
+  }
+
+  function movePopup() {
+    if (!opened) {
+      return;
+    }
+    const { x, y } = place(opened);
+    popup.style.left = `${x}px`;
+    popup.style.top = `${y}px`;
+  }
+
+  function closePopup() {
+    opened = null;
+    popup.hidden = true;
+  }
+
+  function reported(published) {
+    if (published === null || published === undefined) {
+      return "Unknown";
+    }
+    const minutes = Math.round((Date.now() - published) / 60000);
+    return `${new Date(published).toLocaleString()} (${minutes} min ago)`;
+  }
+
+  /********
+   * View *
+   ********/
+
+  function redraw() {
+    confine();
+    drawTiles();
+    drawPins();
+    movePopup();
+    zoomLevel.textContent = `z${view.z}`;
+  }
+
+  // Let the view settle before asking for alerts and saving it
+  function settled() {
+    clearTimeout(waiting);
+    waiting = setTimeout(() => {
+      loadAlerts();
+      const centre = unproject(view.x, view.y, view.z);
+      history.replaceState(
+        null,
+        "",
+        `#${layer.name}/${view.z}/${centre.lat.toFixed(5)}/${centre.lon.toFixed(5)}`,
+      );
+    }, 300);
+  }
+
+  // Change zoom keeping the point under the pointer still
+  function zoomTo(z, offsetX = map.clientWidth / 2, offsetY = map.clientHeight / 2) {
+    z = Math.max(layer.minzoom, Math.min(layer.maxzoom, z));
+    if (z === view.z) {
+      return;
+    }
+    const { left, top } = corner();
+    const scale = 2 ** (z - view.z);
+    view.x = (left + offsetX) * scale - (offsetX - map.clientWidth / 2);
+    view.y = (top + offsetY) * scale - (offsetY - map.clientHeight / 2);
+    view.z = z;
+    clearTiles();
+    redraw();
+    settled();
+  }
+
+  async function chooseLayer(name) {
+    const response = await fetch(`/${encodeURIComponent(name)}.json`);
+    if (!response.ok) {
+      status.textContent = `Layer ${name} could not be loaded`;
+      return;
+    }
+    const centre = unproject(view.x, view.y, view.z);
+    layer = await response.json();
+    layerSelect.value = layer.name;
+    attribution.textContent = layer.attribution || "";
+
+    // Same place within the zooms of the new layer
+    view.z = Math.max(layer.minzoom, Math.min(layer.maxzoom, view.z));
+    const point = project(centre.lat, centre.lon, view.z);
+    view.x = point.x;
+    view.y = point.y;
+    clearTiles();
+    redraw();
+    settled();
+  }
+
+  /**********
+   * Events *
+   **********/
+
+  let dragging = null;
+
+  map.addEventListener("pointerdown", (event) => {
+    if (event.target.closest("#popup")) {
+      return;
+    }
+    dragging = { x: event.clientX, y: event.clientY, moved: false };
+    map.setPointerCapture(event.pointerId);
+    map.classList.add("dragging");
+  });
+
+  map.addEventListener("pointermove", (event) => {
+    if (!dragging) {
+      return;
+    }
+    view.x -= event.clientX - dragging.x;
+    view.y -= event.clientY - dragging.y;
+    dragging.moved = dragging.moved || event.clientX !== dragging.x || event.clientY !== dragging.y;
+    dragging.x = event.clientX;
+    dragging.y = event.clientY;
+    redraw();
+  });
+
+  map.addEventListener("pointerup", () => {
+    if (!dragging) {
+      return;
+    }
+    if (dragging.moved) {
+      settled();
+    } else {
+      closePopup();
+    }
+    dragging = null;
+    map.classList.remove("dragging");
+  });
+
+  map.addEventListener(
+    "wheel",
+    (event) => {
+      event.preventDefault();
+      const bounds = map.getBoundingClientRect();
+      zoomTo(view.z + (event.deltaY < 0 ? 1 : -1), event.clientX - bounds.left, event.clientY - bounds.top);
+    },
+    { passive: false },
+  );
+
+  map.addEventListener("dblclick", (event) => {
+    const bounds = map.getBoundingClientRect();
+    zoomTo(view.z + 1, event.clientX - bounds.left, event.clientY - bounds.top);
+  });
+
+  document.getElementById("zoom-in").addEventListener("click", () => zoomTo(view.z + 1));
+  document.getElementById("zoom-out").addEventListener("click", () => zoomTo(view.z - 1));
+  layerSelect.addEventListener("change", () => chooseLayer(layerSelect.value));
+  showPins.addEventListener("change", drawPins);
+  severity.addEventListener("input", () => {
+    severityValue.textContent = severity.value;
+    closePopup();
+    drawPins();
+  });
+  window.addEventListener("resize", redraw);
+
+  /*********
+   * Start *
+   *********/
+
+  async function start() {
+    const response = await fetch("/viewer/layers");
+    const names = await response.json();
+    for (const name of names) {
+      layerSelect.add(new Option(name, name));
+    }
+    if (names.length === 0) {
+      status.textContent = "No layers are served";
+      return;
+    }
+
+    // Place kept in the address as #layer/zoom/latitude/longitude
+    const [name, z, lat, lon] = decodeURIComponent(location.hash.slice(1)).split("/");
+    const chosen = names.includes(name) ? name : names.includes("default") ? "default" : names[0];
+    if (z !== undefined && lat !== undefined && lon !== undefined) {
+      view.z = Number(z) || 0;
+      const point = project(Number(lat) || 0, Number(lon) || 0, view.z);
+      view.x = point.x;
+      view.y = point.y;
+    } else {
+      view.x = worldSize(view.z) / 2;
+      view.y = worldSize(view.z) / 2;
+    }
+    await chooseLayer(chosen);
+  }
+
+  start();
+})();
@@ -0,0 +1,499 @@
+/***********
+ * Imports *
+ ***********/
+
+// Mine
+use crate::cache;
+use crate::config;
+use crate::error;
+use crate::render;
+use crate::tilejson;
+use crate::tiles;
+use crate::utils;
+use crate::NAME;
+
+// HTTP
+use axum::{
+    extract::{
+        rejection::{PathRejection, QueryRejection},
+        ConnectInfo, Path, Query, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use axum_extra::{headers::UserAgent, TypedHeader};
+
+// Data
+use serde::Deserialize;
+
+// Arguments
+use clap::ValueEnum;
+
+// Standard
+use std::collections::HashMap;
+use std::f64::consts;
+use std::fmt::Write;
+use std::net::SocketAddr;
+
+/*************
+ * Variables *
+ *************/
+
+const VERSION: &str = "1.0.0";
+
+// Only tile matrix set and style there are, the set is Web Mercator but with
+// tiles twice the size of GoogleMapsCompatible so it cannot take its name
+const MATRIX_SET: &str = "WebMercatorQuad512";
+const STYLE: &str = "default";
+
+// Size of a pixel when working out scales
+const PIXEL_SIZE: f64 = 0.00028; // Metres
+
+/***********
+ * Structs *
+ ***********/
+
+// Path of a tile as a resource
+#[derive(Deserialize)]
+pub struct Resource {
+    layer: String,
+    style: String,
+    set: String,
+    z: u16,
+    y: u32,
+    // Column and extension
+    tile: String,
+}
+
+// OWS exception report
+pub struct Exception {
+    status: StatusCode,
+    code: &'static str,
+    locator: Option<&'static str>,
+    message: String,
+}
+
+/*************
+ * Functions *
+ *************/
+
+pub fn routes() -> Router<cache::Caches> {
+    Router::new()
+        .route("/", get(kvp))
+        .route("/1.0.0/WMTSCapabilities.xml", get(capabilities))
+        .route("/1.0.0/{layer}/{style}/{set}/{z}/{y}/{tile}", get(rest))
+}
+
+impl Exception {
+    fn new(
+        status: StatusCode,
+        code: &'static str,
+        locator: Option<&'static str>,
+        message: impl Into<String>,
+    ) -> Self {
+        Exception {
+            status,
+            code,
+            locator,
+            message: message.into(),
+        }
+    }
+
+    fn missing(locator: &'static str) -> Self {
+        Exception::new(
+            StatusCode::BAD_REQUEST,
+            "MissingParameterValue",
+            Some(locator),
+            format!("{} is needed", locator),
+        )
+    }
+
+    fn invalid(locator: &'static str, message: impl Into<String>) -> Self {
+        Exception::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidParameterValue",
+            Some(locator),
+            message,
+        )
+    }
+}
+
+// Errors of the tiles in the terms of WMTS
+impl From<error::Failure> for Exception {
+    fn from(failure: error::Failure) -> Self {
+        let (status, error, message) = failure.into_parts();
+        match error {
+            "invalid_tile" | "zoom_out_of_range" => Exception::new(
+                StatusCode::BAD_REQUEST,
+                "TileOutOfRange",
+                Some("tilematrix"),
+                message,
+            ),
+            "unknown_layer" => Exception::invalid("layer", message),
+            "unknown_format" => Exception::invalid("format", message),
+            _ => Exception::new(status, "NoApplicableCode", None, message),
+        }
+    }
+}
+
+impl IntoResponse for Exception {
+    fn into_response(self) -> Response {
+        let locator = self
+            .locator
+            .map(|locator| format!(r#" locator="{}""#, locator))
+            .unwrap_or_default();
+        let body = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<ExceptionReport xmlns="http://www.opengis.net/ows/1.1" version="1.1.0">
+  <Exception exceptionCode="{}"{}>
+    <ExceptionText>{}</ExceptionText>
+  </Exception>
+</ExceptionReport>
+"#,
+            self.code,
+            locator,
+            escape(&self.message)
+        );
+
+        (
+            self.status,
+            [
+                (header::SERVER, NAME),
+                (header::CONTENT_TYPE, "application/xml"),
+            ],
+            body,
+        )
+            .into_response()
+    }
+}
+
+// Key value pairs, names are not case sensitive
+async fn kvp(
+    State(caches): State<cache::Caches>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    raw_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    arguments: Result<Query<HashMap<String, String>>, QueryRejection>,
+) -> Result<Response, Exception> {
+    let Query(arguments) = arguments.map_err(|rejection| {
+        Exception::new(
+            StatusCode::BAD_REQUEST,
+            "NoApplicableCode",
+            None,
+            rejection.body_text(),
+        )
+    })?;
+    let arguments: HashMap<String, String> = arguments
+        .into_iter()
+        .map(|(key, value)| (key.to_lowercase(), value))
+        .collect();
+    let argument = |name: &'static str| {
+        arguments
+            .get(name)
+            .map(String::as_str)
+            .ok_or_else(|| Exception::missing(name))
+    };
+
+    if !argument("service")?.eq_ignore_ascii_case("WMTS") {
+        return Err(Exception::invalid("service", "Only WMTS is served"));
+    }
+
+    match argument("request")? {
+        "GetCapabilities" => Ok(capabilities(headers).await),
+        "GetTile" => {
+            check(argument("style")?, argument("tilematrixset")?)?;
+            let format = argument("format")?;
+            let format = config::Format::value_variants()
+                .iter()
+                .copied()
+                .find(|known| known.mime() == format)
+                .ok_or_else(|| {
+                    Exception::invalid("format", format!("Format {} is not supported", format))
+                })?;
+            let matrix = argument("tilematrix")?;
+            let z = matrix
+                .rsplit(':')
+                .next()
+                .and_then(|z| z.parse().ok())
+                .ok_or_else(|| {
+                    Exception::invalid("tilematrix", format!("{} is not a zoom", matrix))
+                })?;
+            let number = |name: &'static str| {
+                argument(name)?
+                    .parse::<u32>()
+                    .map_err(|_| Exception::invalid(name, format!("{} is not a number", name)))
+            };
+            let given_xyz = utils::XYZ {
+                x: number("tilecol")?,
+                y: number("tilerow")?,
+                z,
+            };
+
+            Ok(tiles::serve_tile(
+                &caches,
+                &addr,
+                raw_agent.as_deref(),
+                &headers,
+                argument("layer")?,
+                &given_xyz,
+                Some(format),
+            )
+            .await?)
+        }
+        request => Err(Exception::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "OperationNotSupported",
+            Some("request"),
+            format!("{} is not supported", request),
+        )),
+    }
+}
+
+// Tile as a resource
+async fn rest(
+    State(caches): State<cache::Caches>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    raw_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    path: Result<Path<Resource>, PathRejection>,
+) -> Result<Response, Exception> {
+    let Path(resource) = path.map_err(|rejection| {
+        Exception::new(
+            StatusCode::BAD_REQUEST,
+            "NoApplicableCode",
+            None,
+            rejection.body_text(),
+        )
+    })?;
+    check(&resource.style, &resource.set)?;
+
+    // The column comes with the format as its extension
+    let (x, extension) = resource
+        .tile
+        .split_once('.')
+        .ok_or_else(|| Exception::missing("format"))?;
+    let format = config::Format::from_extension(extension).ok_or_else(|| {
+        Exception::invalid("format", format!("Format {} is not supported", extension))
+    })?;
+    let x = x
+        .parse()
+        .map_err(|_| Exception::invalid("tilecol", format!("{} is not a number", x)))?;
+    let given_xyz = utils::XYZ {
+        x,
+        y: resource.y,
+        z: resource.z,
+    };
+
+    Ok(tiles::serve_tile(
+        &caches,
+        &addr,
+        raw_agent.as_deref(),
+        &headers,
+        &resource.layer,
+        &given_xyz,
+        Some(format),
+    )
+    .await?)
+}
+
+// Style and tile matrix set are always the same ones
+fn check(style: &str, set: &str) -> Result<(), Exception> {
+    if !style.is_empty() && style != STYLE {
+        return Err(Exception::invalid(
+            "style",
+            format!("Style {} does not exist", style),
+        ));
+    }
+    if set != MATRIX_SET {
+        return Err(Exception::invalid(
+            "tilematrixset",
+            format!("Tile matrix set {} does not exist", set),
+        ));
+    }
+
+    Ok(())
+}
+
+// Layers, their formats and the tile matrix set
+async fn capabilities(headers: HeaderMap) -> Response {
+    let config = config::get();
+    let base = tilejson::public_url(&headers);
+    let mut xml = String::new();
+
+    // Service and operations
+    write!(
+        xml,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Capabilities xmlns="http://www.opengis.net/wmts/1.0" xmlns:ows="http://www.opengis.net/ows/1.1" xmlns:xlink="http://www.w3.org/1999/xlink" version="{version}">
+  <ows:ServiceIdentification>
+    <ows:Title>{name}</ows:Title>
+    <ows:ServiceType>OGC WMTS</ows:ServiceType>
+    <ows:ServiceTypeVersion>{version}</ows:ServiceTypeVersion>
+  </ows:ServiceIdentification>
+  <ows:OperationsMetadata>
+"#,
+        version = VERSION,
+        name = NAME,
+    )
+    .unwrap();
+    for operation in ["GetCapabilities", "GetTile"] {
+        write!(
+            xml,
+            r#"    <ows:Operation name="{operation}">
+      <ows:DCP>
+        <ows:HTTP>
+          <ows:Get xlink:href="{base}/wmts?">
+            <ows:Constraint name="GetEncoding">
+              <ows:AllowedValues>
+                <ows:Value>KVP</ows:Value>
+              </ows:AllowedValues>
+            </ows:Constraint>
+          </ows:Get>
+        </ows:HTTP>
+      </ows:DCP>
+    </ows:Operation>
+"#,
+            base = escape(&base),
+        )
+        .unwrap();
+    }
+    xml.push_str("  </ows:OperationsMetadata>\n  <Contents>\n");
+
+    // Layers
+    for (name, layer) in config.layers.iter() {
+        let [west, south, east, north] = layer.bounds;
+        write!(
+            xml,
+            r#"    <Layer>
+      <ows:Title>{name}</ows:Title>
+      <ows:Identifier>{name}</ows:Identifier>
+      <ows:WGS84BoundingBox>
+        <ows:LowerCorner>{west} {south}</ows:LowerCorner>
+        <ows:UpperCorner>{east} {north}</ows:UpperCorner>
+      </ows:WGS84BoundingBox>
+      <Style isDefault="true">
+        <ows:Identifier>{STYLE}</ows:Identifier>
+      </Style>
+"#,
+            name = escape(name),
+        )
+        .unwrap();
+        for format in layer.output.formats.iter() {
+            writeln!(xml, "      <Format>{}</Format>", format.mime()).unwrap();
+        }
+
+        // Tiles within the bounds of each served zoom
+        write!(
+            xml,
+            "      <TileMatrixSetLink>\n        <TileMatrixSet>{MATRIX_SET}</TileMatrixSet>\n        <TileMatrixSetLimits>\n"
+        )
+        .unwrap();
+        for z in render::served_zooms(layer) {
+            let top = utils::coordinate_to_xyz(
+                &utils::Coordinate {
+                    lat: north,
+                    lon: west,
+                },
+                z,
+            );
+            let bottom = utils::coordinate_to_xyz(
+                &utils::Coordinate {
+                    lat: south,
+                    lon: east,
+                },
+                z,
+            );
+            write!(
+                xml,
+                r#"          <TileMatrixLimits>
+            <TileMatrix>{z}</TileMatrix>
+            <MinTileRow>{}</MinTileRow>
+            <MaxTileRow>{}</MaxTileRow>
+            <MinTileCol>{}</MinTileCol>
+            <MaxTileCol>{}</MaxTileCol>
+          </TileMatrixLimits>
+"#,
+                top.y, bottom.y, top.x, bottom.x
+            )
+            .unwrap();
+        }
+        xml.push_str("        </TileMatrixSetLimits>\n      </TileMatrixSetLink>\n");
+
+        for format in layer.output.formats.iter() {
+            writeln!(
+                xml,
+                r#"      <ResourceURL format="{}" resourceType="tile" template="{}/wmts/{VERSION}/{}/{{Style}}/{{TileMatrixSet}}/{{TileMatrix}}/{{TileRow}}/{{TileCol}}.{}"/>"#,
+                format.mime(),
+                escape(&base),
+                escape(name),
+                format.extension()
+            )
+            .unwrap();
+        }
+        xml.push_str("    </Layer>\n");
+    }
+
+    // Web Mercator from the top left corner of the world
+    let edge = consts::PI * utils::EARTH_RADIUS;
+    let zooms = config
+        .layers
+        .values()
+        .map(|layer| *render::served_zooms(layer).end())
+        .max()
+        .unwrap_or(0);
+    write!(
+        xml,
+        "    <TileMatrixSet>\n      <ows:Identifier>{MATRIX_SET}</ows:Identifier>\n      <ows:SupportedCRS>urn:ogc:def:crs:EPSG::3857</ows:SupportedCRS>\n"
+    )
+    .unwrap();
+    for z in 0..=zooms {
+        let count = 1_u64 << z;
+        let resolution = (2.0 * edge) / (f64::from(utils::TILE_SIZE) * count as f64);
+        write!(
+            xml,
+            r#"      <TileMatrix>
+        <ows:Identifier>{z}</ows:Identifier>
+        <ScaleDenominator>{}</ScaleDenominator>
+        <TopLeftCorner>{} {}</TopLeftCorner>
+        <TileWidth>{size}</TileWidth>
+        <TileHeight>{size}</TileHeight>
+        <MatrixWidth>{count}</MatrixWidth>
+        <MatrixHeight>{count}</MatrixHeight>
+      </TileMatrix>
+"#,
+            resolution / PIXEL_SIZE,
+            -edge,
+            edge,
+            size = utils::TILE_SIZE,
+        )
+        .unwrap();
+    }
+    xml.push_str("    </TileMatrixSet>\n  </Contents>\n");
+    writeln!(
+        xml,
+        r#"  <ServiceMetadataURL xlink:href="{}/wmts/{VERSION}/WMTSCapabilities.xml"/>"#,
+        escape(&base)
+    )
+    .unwrap();
+    xml.push_str("</Capabilities>\n");
+
+    (
+        [
+            (header::SERVER, NAME),
+            (header::CONTENT_TYPE, "application/xml"),
+        ],
+        xml,
+    )
+        .into_response()
+}
+
+// Text that is safe inside of XML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}